@@ -1,6 +1,6 @@
 // shamelessly stolen from Raytwo's Arcropolis :))))))))
 
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::collections::HashMap;
 // note, this implementation is only here to serve HDR's needs and is in no way a definitive file addition solution
 // but hell fucking yeah
@@ -176,8 +176,10 @@ impl LoadedTables {
 }
 
 static INIT: std::sync::Once = std::sync::Once::new();
+static FOUND: AtomicBool = AtomicBool::new(false);
 
-pub fn init() {
+// Finds the LoadedTables in the game's code, returning whether it was found. Only searches the first time it is called
+pub fn init() -> bool {
     INIT.call_once(|| {
         unsafe {
             let text_ptr = skyline::hooks::getRegionAddress(skyline::hooks::Region::Text) as *const u8;
@@ -188,9 +190,11 @@ pub fn init() {
                 let _adrp_offset = crate::utils::offset_from_adrp(adrp_offset);
                 let ldr_offset = crate::utils::offset_from_ldr(adrp_offset + 4);
                 LOADED_TABLES_OFFSET = _adrp_offset + ldr_offset;
+                FOUND.store(true, Ordering::Release);
             } else {
-                panic!("Failed to find LoadedTables offset.");
+                println!("[HDR::ArcRuntime] Failed to find LoadedTables offset.");
             }
         }
     });
+    FOUND.load(Ordering::Acquire)
 }
\ No newline at end of file
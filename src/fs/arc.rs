@@ -0,0 +1,150 @@
+// Read-through mount for the game's data.arc
+// Paths on the `arc:/` mount are either arc paths (`arc:/fighter/mario/param/vl.prc`) or raw hashes
+// (`arc:/0x0d8b6d2a7e`). Lookups and decompression are done through smash-arc, so the same mount works
+// against the game's LoadedArc at runtime and against an `ArcFile` opened from disk.
+// The runtime mount (`ArcMount::runtime`, registered as `arc:/` by `fs::init`) is only built with the `arc_runtime`
+// feature, which is off by default. Without it `arc:/` is not mounted and only `ArcMount::from_arc` is available.
+use smash_arc::{ArcLookup, Hash40, LookupError, Region};
+use super::{LoadError, Mount};
+
+/// The lookups the `arc:/` mount needs, implemented for everything smash-arc can look files up in
+pub trait ArcSource: 'static {
+    fn file_contents(&self, hash: Hash40, region: Region) -> Result<Vec<u8>, LookupError>;
+
+    fn has_file(&self, hash: Hash40) -> bool;
+}
+
+impl<A: ArcLookup + 'static> ArcSource for A {
+    fn file_contents(&self, hash: Hash40, region: Region) -> Result<Vec<u8>, LookupError> {
+        self.get_file_contents(hash, region)
+    }
+
+    fn has_file(&self, hash: Hash40) -> bool {
+        self.get_file_path_index_from_hash(hash).is_ok()
+    }
+}
+
+pub struct ArcMount<A: ArcSource> {
+    arc: Box<dyn Fn() -> Result<&'static A, LoadError> + Send + Sync>,
+    region: Region
+}
+
+impl<A: ArcSource + Sync> ArcMount<A> {
+    /// Creates a mount which reads from `arc` for the rest of the program, for example an `ArcFile` opened from disk
+    pub fn from_arc(arc: A) -> Self {
+        let arc: &'static A = Box::leak(Box::new(arc));
+        Self {
+            arc: Box::new(move || Ok(arc)),
+            region: Region::None
+        }
+    }
+}
+
+#[cfg(feature = "arc_runtime")]
+impl ArcMount<smash_arc::LoadedArc> {
+    /// Creates a mount which reads from the data.arc the game has loaded.
+    /// Reads fail with `LoadError::BadMount` if the game's arc tables can't be found.
+    pub fn runtime() -> Self {
+        Self {
+            arc: Box::new(|| {
+                if !crate::arc_runtime::init() {
+                    return Err(LoadError::BadMount(String::from("arc")));
+                }
+                Ok(&*crate::arc_runtime::LoadedTables::get_instance().loaded_data.arc)
+            }),
+            region: Region::None
+        }
+    }
+}
+
+impl<A: ArcSource> ArcMount<A> {
+    /// Sets the region used to look up regional files
+    pub fn with_region(mut self, region: Region) -> Self {
+        self.region = region;
+        self
+    }
+
+    fn hash(path: &str) -> Hash40 {
+        let path = path.trim_start_matches('/');
+        if let Some(hex) = path.strip_prefix("0x") {
+            if let Ok(hash) = u64::from_str_radix(hex, 16) {
+                return Hash40(hash);
+            }
+        }
+        Hash40::from(path)
+    }
+}
+
+impl<A: ArcSource> Mount for ArcMount<A> {
+    fn read(&self, path: &str) -> Result<Vec<u8>, LoadError> {
+        (self.arc)()?.file_contents(Self::hash(path), self.region).map_err(|err| match err {
+            LookupError::Missing => LoadError::NotFound,
+            err => {
+                println!("[HDR::FileManager] Failed to read \"{}\" from data.arc: {:?}", path, err);
+                LoadError::Io(std::io::ErrorKind::InvalidData)
+            }
+        })
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        (self.arc)().map_or(false, |arc| arc.has_file(Self::hash(path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Stands in for a data.arc, without needing one on disk
+    struct TestArc(HashMap<u64, Vec<u8>>);
+
+    impl ArcSource for TestArc {
+        fn file_contents(&self, hash: Hash40, _: Region) -> Result<Vec<u8>, LookupError> {
+            self.0.get(&hash.0).cloned().ok_or(LookupError::Missing)
+        }
+
+        fn has_file(&self, hash: Hash40) -> bool {
+            self.0.contains_key(&hash.0)
+        }
+    }
+
+    #[test]
+    fn reads_by_path_and_hash() {
+        let hash = Hash40::from("fighter/mario/param/vl.prc");
+        let mut files = HashMap::new();
+        files.insert(hash.0, b"vl".to_vec());
+        let mount = ArcMount::from_arc(TestArc(files));
+
+        assert_eq!(mount.read("fighter/mario/param/vl.prc"), Ok(b"vl".to_vec()));
+        assert_eq!(mount.read("/fighter/mario/param/vl.prc"), Ok(b"vl".to_vec()));
+        assert_eq!(mount.read(&format!("0x{:x}", hash.0)), Ok(b"vl".to_vec()));
+        assert_eq!(mount.read("fighter/luigi/param/vl.prc"), Err(LoadError::NotFound));
+        assert!(mount.is_file("fighter/mario/param/vl.prc"));
+        assert!(!mount.is_file("fighter/luigi/param/vl.prc"));
+        // the arc can't be listed, but files are still known about
+        assert_eq!(mount.list_dir("fighter/mario/param"), Ok(Vec::new()));
+        assert_eq!(mount.metadata("fighter/mario/param/vl.prc").map(|x| x.size), Ok(2));
+    }
+
+    // Reads through smash-arc's own lookup tables and decompression. Needs a data.arc, set HDR_TEST_ARC to its path
+    #[test]
+    fn reads_through_smash_arc() {
+        let path = match std::env::var("HDR_TEST_ARC") {
+            Ok(path) => path,
+            Err(_) => {
+                println!("HDR_TEST_ARC is not set, skipping");
+                return;
+            }
+        };
+        let arc = smash_arc::ArcFile::open(&path).expect("Failed to open HDR_TEST_ARC");
+        let expected = arc.get_file_contents(Hash40::from("fighter/common/param/fighter_param.prc"), Region::None).unwrap();
+        let mount = ArcMount::from_arc(arc);
+        let data = mount.read("fighter/common/param/fighter_param.prc").unwrap();
+        // the stored file is zstd compressed, what comes out has to be the decompressed prc
+        assert_eq!(&data[..8], b"paracobn");
+        assert_eq!(data, expected);
+        assert!(mount.is_file("fighter/common/param/fighter_param.prc"));
+        assert_eq!(mount.read("fighter/common/param/hdrtest_missing.prc"), Err(LoadError::NotFound));
+    }
+}
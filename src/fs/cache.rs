@@ -0,0 +1,211 @@
+// Bounded cache of raw file contents
+// Fighter NROs are loaded and unloaded every time a fighter is picked, which re-reads the same files from disk.
+// Reads through the file manager keep the most recently used files in memory, up to a byte budget, so that loading
+// the same fighter again doesn't have to touch the disk. Entries are keyed by the full path the file was read from
+// and remember the modification time it had, so that edited files are never served from the cache. Files on mounts
+// which can't tell when a file changed (arc, hdrpak, ...) are never cached, since a stale entry could never be noticed.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+use parking_lot::Mutex;
+use lazy_static::lazy_static;
+
+struct CacheEntry {
+    modified: Option<SystemTime>,
+    data: Arc<Vec<u8>>,
+    last_used: u64
+}
+
+struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    size: usize,
+    // Incremented on every access, the entry with the lowest `last_used` is the least recently used one
+    clock: u64
+}
+
+impl Cache {
+    fn remove(&mut self, path: &str) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.size -= entry.data.len();
+        }
+    }
+
+    fn evict_to(&mut self, budget: usize) {
+        while self.size > budget {
+            let oldest = match self.entries.iter().min_by_key(|(_, entry)| entry.last_used) {
+                Some((path, _)) => path.clone(),
+                None => break
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn lookup(&mut self, path: &str, modified: Option<SystemTime>) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(path) {
+            Some(entry) if entry.modified == modified => {
+                entry.last_used = clock;
+                Some(entry.data.clone())
+            },
+            Some(_) => {
+                // stale, the file has changed since it was cached
+                self.remove(path);
+                None
+            },
+            None => None
+        }
+    }
+
+    fn store(&mut self, budget: usize, path: &str, modified: Option<SystemTime>, data: &[u8]) {
+        // files which don't fit would only evict everything else
+        if data.len() > budget {
+            return;
+        }
+        self.remove(path);
+        self.evict_to(budget - data.len());
+        self.clock += 1;
+        let last_used = self.clock;
+        self.size += data.len();
+        self.entries.insert(String::from(path), CacheEntry {
+            modified,
+            data: Arc::new(data.to_vec()),
+            last_used
+        });
+    }
+}
+
+const DEFAULT_CACHE_BUDGET: usize = 0x100_0000;
+static CACHE_BUDGET: AtomicUsize = AtomicUsize::new(DEFAULT_CACHE_BUDGET);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache {
+        entries: HashMap::new(),
+        size: 0,
+        clock: 0
+    });
+}
+
+/// Sets how many bytes of file contents are kept in memory, evicting the least recently used files if the cache is
+/// already larger than that. A budget of 0 disables the cache.
+pub fn set_cache_budget(bytes: usize) {
+    CACHE_BUDGET.store(bytes, Ordering::Release);
+    CACHE.lock().evict_to(bytes);
+}
+
+/// Drops every cached file
+pub fn clear_cache() {
+    let mut cache = CACHE.lock();
+    cache.entries.clear();
+    cache.size = 0;
+}
+
+// The cached contents of `path`, if they were cached with the same modification time
+pub(crate) fn get(path: &str, modified: Option<SystemTime>) -> Option<Vec<u8>> {
+    if CACHE_BUDGET.load(Ordering::Acquire) == 0 || modified.is_none() {
+        return None;
+    }
+    let data = CACHE.lock().lookup(path, modified);
+    match data {
+        Some(data) => {
+            HITS.fetch_add(1, Ordering::AcqRel);
+            Some(data.as_ref().clone())
+        },
+        None => {
+            MISSES.fetch_add(1, Ordering::AcqRel);
+            None
+        }
+    }
+}
+
+pub(crate) fn insert(path: &str, modified: Option<SystemTime>, data: &[u8]) {
+    if modified.is_none() {
+        return;
+    }
+    let budget = CACHE_BUDGET.load(Ordering::Acquire);
+    CACHE.lock().store(budget, path, modified, data);
+}
+
+pub(crate) fn invalidate(path: &str) {
+    CACHE.lock().remove(path);
+}
+
+// (hits, misses, bytes currently cached)
+pub(crate) fn counters() -> (u64, u64, usize) {
+    (HITS.load(Ordering::Acquire), MISSES.load(Ordering::Acquire), CACHE.lock().size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cache() -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            size: 0,
+            clock: 0
+        }
+    }
+
+    fn cached(cache: &mut Cache, path: &str) -> Option<Vec<u8>> {
+        cache.lookup(path, None).map(|x| x.as_ref().clone())
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = cache();
+        cache.store(8, "a", None, &[1, 1, 1]);
+        cache.store(8, "b", None, &[2, 2, 2]);
+        // touching `a` makes `b` the least recently used file
+        assert_eq!(cached(&mut cache, "a"), Some(vec![1, 1, 1]));
+        cache.store(8, "c", None, &[3, 3, 3]);
+        assert_eq!(cached(&mut cache, "b"), None);
+        assert_eq!(cached(&mut cache, "a"), Some(vec![1, 1, 1]));
+        assert_eq!(cached(&mut cache, "c"), Some(vec![3, 3, 3]));
+        assert_eq!(cache.size, 6);
+    }
+
+    #[test]
+    fn stays_within_budget() {
+        let mut cache = cache();
+        cache.store(4, "a", None, &[1, 1]);
+        // too large to ever fit, so nothing is evicted for it
+        cache.store(4, "big", None, &[0; 5]);
+        assert_eq!(cached(&mut cache, "big"), None);
+        assert_eq!(cached(&mut cache, "a"), Some(vec![1, 1]));
+        // replacing a file doesn't count its old contents against the budget
+        cache.store(4, "a", None, &[2, 2, 2, 2]);
+        assert_eq!(cache.size, 4);
+        cache.evict_to(0);
+        assert_eq!(cache.size, 0);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn skips_files_without_a_modification_time() {
+        insert("hdrtest-cache:/untracked.bin", None, &[1, 2, 3]);
+        assert!(!CACHE.lock().entries.contains_key("hdrtest-cache:/untracked.bin"));
+        assert_eq!(get("hdrtest-cache:/untracked.bin", None), None);
+
+        let modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2));
+        insert("hdrtest-cache:/tracked.bin", modified, &[4]);
+        assert_eq!(get("hdrtest-cache:/tracked.bin", modified), Some(vec![4]));
+        invalidate("hdrtest-cache:/tracked.bin");
+    }
+
+    #[test]
+    fn drops_stale_entries() {
+        let mut cache = cache();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        cache.store(16, "a", Some(modified), &[1]);
+        assert!(cache.lookup("a", Some(modified)).is_some());
+        assert!(cache.lookup("a", Some(modified + Duration::from_secs(1))).is_none());
+        // the stale entry is gone for good, even when asked with the old time again
+        assert!(cache.lookup("a", Some(modified)).is_none());
+        assert_eq!(cache.size, 0);
+    }
+}
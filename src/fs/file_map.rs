@@ -0,0 +1,450 @@
+// Parsing and validation of HDR's file map
+// The file map tells the file manager which files to load when an NRO is loaded. Two formats are accepted:
+//
+// Version 1, a plain object of NRO names to file paths, which may also contain `"version": 1`:
+//   { "common": ["rom:/hdr/common/common.prc"] }
+//
+// Version 2, which allows every entry to carry extra information:
+//   {
+//       "version": 2,
+//       "auto_discover": true, // also load every file under rom:/hdr/fighter/<NRO name>/, defaults to false
+//       "modules": {
+//           "mario": [
+//               "rom:/hdr/mario/param.prc",
+//               "rom:/hdr/mario/moves/*.prc", // globs are expanded when the NRO is loaded
+//               {
+//                   "path": "sd:/hdr/mario/extra.prc",
+//                   "handler": "prc",           // pattern of the registered handler to use instead of matching the path
+//                   "priority": 10,             // higher priorities are queued first, defaults to 0
+//                   "optional": true,           // missing files are not reported as errors, defaults to false
+//                   "load_priority": "background", // "critical", "normal" or "background", defaults to "normal"
+//                   "condition": "config:beta", // "feature:<cargo feature>" or "config:<key>", prefix with '!' to negate
+//                   "depends": ["rom:/hdr/common/common.prc"]
+//               }
+//           ]
+//       }
+//   }
+//
+// Parsing never stops at the first problem, every invalid part of the file map is reported as a `Diagnostic`
+// with the JSON path of the offending value, and skipped.
+use std::collections::HashMap;
+use parking_lot::RwLock;
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use super::LoadPriority;
+
+pub const LATEST_VERSION: u64 = 2;
+
+/// A condition that decides whether a file map entry is loaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// Met when the cargo feature was enabled when building hdr-core
+    Feature(String),
+    /// Met when the key was set to true with `set_config`
+    Config(String),
+    /// Met when the inner condition is not
+    Not(Box<Condition>)
+}
+
+impl Condition {
+    pub fn parse(condition: &str) -> Option<Self> {
+        if let Some(inner) = condition.strip_prefix('!') {
+            return Self::parse(inner).map(|x| Condition::Not(Box::new(x)));
+        }
+        if let Some(feature) = condition.strip_prefix("feature:") {
+            Some(Condition::Feature(String::from(feature)))
+        } else if let Some(key) = condition.strip_prefix("config:") {
+            Some(Condition::Config(String::from(key)))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_met(&self) -> bool {
+        match self {
+            Condition::Feature(feature) => match feature.as_str() {
+                "debug" => cfg!(feature = "debug"),
+                "no_common_params" => cfg!(feature = "no_common_params"),
+                "arc_runtime" => cfg!(feature = "arc_runtime"),
+                _ => false
+            },
+            Condition::Config(key) => get_config(key),
+            Condition::Not(inner) => !inner.is_met()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileMapEntry {
+    pub path: String,
+    pub handler: Option<String>,
+    pub priority: i64,
+    pub optional: bool,
+    /// The class the file is loaded with, independent of whether it is optional
+    pub load_priority: LoadPriority,
+    pub condition: Option<Condition>,
+    pub depends: Vec<String>
+}
+
+impl FileMapEntry {
+    pub fn new<S: Into<String>>(path: S) -> Self {
+        Self {
+            path: path.into(),
+            handler: None,
+            priority: 0,
+            optional: false,
+            load_priority: LoadPriority::Normal,
+            condition: None,
+            depends: Vec::new()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.condition.as_ref().map_or(true, |x| x.is_met())
+    }
+
+    /// Whether this entry applies to `path`, either by naming it directly or through a glob
+    pub fn matches(&self, path: &str) -> bool {
+        self.path == path || (super::glob::is_pattern(&self.path) && super::glob::matches(&self.path, path))
+    }
+}
+
+/// A problem found in the file map, `path` is the JSON path of the value it was found at
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub path: String,
+    pub message: String
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileMap {
+    pub version: u64,
+    pub auto_discover: bool,
+    pub modules: HashMap<String, Vec<FileMapEntry>>
+}
+
+struct Parser {
+    diagnostics: Vec<Diagnostic>
+}
+
+impl Parser {
+    fn error<S: Into<String>>(&mut self, path: &str, message: S) {
+        self.diagnostics.push(Diagnostic {
+            path: String::from(path),
+            message: message.into()
+        });
+    }
+
+    fn parse_root(&mut self, json: &Value) -> FileMap {
+        let root = match json {
+            Value::Object(root) => root,
+            _ => {
+                self.error("$", "file map must be an object");
+                return FileMap::default();
+            }
+        };
+        match root.get("version") {
+            None => FileMap {
+                version: 1,
+                auto_discover: false,
+                modules: self.parse_modules(root, "$", false)
+            },
+            Some(Value::Number(version)) if version.as_u64() == Some(1) => {
+                // every other key is an NRO name, same as without a version
+                let mut modules = root.clone();
+                modules.remove("version");
+                FileMap {
+                    version: 1,
+                    auto_discover: false,
+                    modules: self.parse_modules(&modules, "$", false)
+                }
+            },
+            Some(Value::Number(version)) if version.as_u64() == Some(LATEST_VERSION) => {
+                for key in root.keys().filter(|x| *x != "version" && *x != "modules" && *x != "auto_discover") {
+                    self.error(&format!("$.{}", key), "unknown key");
+                }
+                let auto_discover = match root.get("auto_discover") {
+                    Some(Value::Bool(auto_discover)) => *auto_discover,
+                    Some(_) => {
+                        self.error("$.auto_discover", "must be a boolean");
+                        false
+                    },
+                    None => false
+                };
+                let modules = match root.get("modules") {
+                    Some(Value::Object(modules)) => self.parse_modules(modules, "$.modules", true),
+                    Some(_) => {
+                        self.error("$.modules", "must be an object of NRO names to file lists");
+                        HashMap::new()
+                    },
+                    None => {
+                        self.error("$", "missing \"modules\"");
+                        HashMap::new()
+                    }
+                };
+                FileMap {
+                    version: LATEST_VERSION,
+                    auto_discover,
+                    modules
+                }
+            },
+            Some(_) => {
+                self.error("$.version", format!("unsupported file map version, expected 1 or {}", LATEST_VERSION));
+                FileMap::default()
+            }
+        }
+    }
+
+    fn parse_modules(&mut self, modules: &Map<String, Value>, path: &str, allow_objects: bool) -> HashMap<String, Vec<FileMapEntry>> {
+        let mut ret = HashMap::new();
+        for (module, files) in modules.iter() {
+            let module_path = format!("{}.{}", path, module);
+            let files = match files {
+                Value::Array(files) => files,
+                _ => {
+                    self.error(&module_path, "must be an array of files");
+                    continue;
+                }
+            };
+            let mut entries = Vec::with_capacity(files.len());
+            for (idx, file) in files.iter().enumerate() {
+                let entry_path = format!("{}[{}]", module_path, idx);
+                match file {
+                    Value::String(file) => entries.push(FileMapEntry::new(file.as_str())),
+                    Value::Object(file) if allow_objects => {
+                        if let Some(entry) = self.parse_entry(file, &entry_path) {
+                            entries.push(entry);
+                        }
+                    },
+                    _ if allow_objects => self.error(&entry_path, "must be a file path or an entry object"),
+                    _ => self.error(&entry_path, "must be a file path")
+                }
+            }
+            ret.insert(module.clone(), entries);
+        }
+        ret
+    }
+
+    fn parse_entry(&mut self, entry: &Map<String, Value>, path: &str) -> Option<FileMapEntry> {
+        let mut ret = match entry.get("path") {
+            Some(Value::String(file)) => FileMapEntry::new(file.as_str()),
+            Some(_) => {
+                self.error(&format!("{}.path", path), "must be a string");
+                return None;
+            },
+            None => {
+                self.error(path, "missing \"path\"");
+                return None;
+            }
+        };
+        for (key, value) in entry.iter() {
+            let value_path = format!("{}.{}", path, key);
+            match (key.as_str(), value) {
+                ("path", _) => {},
+                ("handler", Value::String(handler)) => ret.handler = Some(handler.clone()),
+                ("handler", _) => self.error(&value_path, "must be a string"),
+                ("priority", Value::Number(priority)) if priority.is_i64() => ret.priority = priority.as_i64().unwrap(),
+                ("priority", _) => self.error(&value_path, "must be an integer"),
+                ("optional", Value::Bool(optional)) => ret.optional = *optional,
+                ("optional", _) => self.error(&value_path, "must be a boolean"),
+                ("load_priority", Value::String(priority)) => match priority.as_str() {
+                    "critical" => ret.load_priority = LoadPriority::Critical,
+                    "normal" => ret.load_priority = LoadPriority::Normal,
+                    "background" => ret.load_priority = LoadPriority::Background,
+                    _ => self.error(&value_path, "must be \"critical\", \"normal\" or \"background\"")
+                },
+                ("load_priority", _) => self.error(&value_path, "must be a string"),
+                ("condition", Value::String(condition)) => match Condition::parse(condition) {
+                    Some(condition) => ret.condition = Some(condition),
+                    None => self.error(&value_path, "must be \"feature:<name>\" or \"config:<key>\", optionally prefixed with '!'")
+                },
+                ("condition", _) => self.error(&value_path, "must be a string"),
+                ("depends", Value::Array(depends)) => {
+                    for (idx, dependency) in depends.iter().enumerate() {
+                        match dependency {
+                            Value::String(dependency) => ret.depends.push(dependency.clone()),
+                            _ => self.error(&format!("{}[{}]", value_path, idx), "must be a file path")
+                        }
+                    }
+                },
+                ("depends", _) => self.error(&value_path, "must be an array of file paths"),
+                _ => self.error(&value_path, "unknown key")
+            }
+        }
+        Some(ret)
+    }
+}
+
+/// Parses a file map of any supported version, skipping whatever is invalid.
+/// Every problem found along the way is returned alongside the parsed file map.
+pub fn parse(json: &Value) -> (FileMap, Vec<Diagnostic>) {
+    let mut parser = Parser {
+        diagnostics: Vec::new()
+    };
+    let file_map = parser.parse_root(json);
+    (file_map, parser.diagnostics)
+}
+
+/// Checks a file map without loading it, returning every problem found
+pub fn validate(json: &str) -> Vec<Diagnostic> {
+    match serde_json::from_str::<Value>(json) {
+        Ok(json) => parse(&json).1,
+        Err(err) => vec![Diagnostic {
+            path: String::from("$"),
+            message: format!("invalid JSON: {}", err)
+        }]
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<HashMap<String, bool>> = RwLock::new(HashMap::new());
+}
+
+/// Sets the value used for `config:<key>` conditions in the file map.
+/// Conditions are checked when an NRO's files are queued, not when the file map is loaded.
+pub fn set_config(key: &str, value: bool) {
+    CONFIG.write().insert(String::from(key), value);
+}
+
+pub fn get_config(key: &str) -> bool {
+    CONFIG.read().get(key).copied().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn diagnostic_paths(diagnostics: &[Diagnostic]) -> Vec<&str> {
+        diagnostics.iter().map(|x| x.path.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_version_1() {
+        let (file_map, diagnostics) = parse(&json!({
+            "common": ["rom:/hdr/common/common.prc"],
+            "mario": ["rom:/hdr/mario/param.prc", 5]
+        }));
+        assert_eq!(file_map.version, 1);
+        assert!(!file_map.auto_discover);
+        assert_eq!(file_map.modules["common"][0].path, "rom:/hdr/common/common.prc");
+        assert_eq!(file_map.modules["mario"].len(), 1);
+        assert_eq!(diagnostic_paths(&diagnostics), vec!["$.mario[1]"]);
+
+        // the version can also be given explicitly
+        let (file_map, diagnostics) = parse(&json!({
+            "version": 1,
+            "mario": ["rom:/hdr/mario/param.prc"]
+        }));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(file_map.version, 1);
+        assert_eq!(file_map.modules.len(), 1);
+        assert_eq!(file_map.modules["mario"][0].path, "rom:/hdr/mario/param.prc");
+    }
+
+    #[test]
+    fn parses_version_2() {
+        let (file_map, diagnostics) = parse(&json!({
+            "version": 2,
+            "auto_discover": true,
+            "modules": {
+                "mario": [
+                    "rom:/hdr/mario/param.prc",
+                    {
+                        "path": "sd:/hdr/mario/extra.prc",
+                        "handler": "prc",
+                        "priority": 10,
+                        "optional": true,
+                        "load_priority": "background",
+                        "condition": "!config:beta",
+                        "depends": ["rom:/hdr/common/common.prc"]
+                    }
+                ]
+            }
+        }));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(file_map.version, 2);
+        assert!(file_map.auto_discover);
+        let entry = &file_map.modules["mario"][1];
+        assert_eq!(entry.path, "sd:/hdr/mario/extra.prc");
+        assert_eq!(entry.handler.as_deref(), Some("prc"));
+        assert_eq!(entry.priority, 10);
+        assert!(entry.optional);
+        assert_eq!(entry.load_priority, LoadPriority::Background);
+        assert_eq!(file_map.modules["mario"][0].load_priority, LoadPriority::Normal);
+        assert_eq!(entry.condition, Some(Condition::Not(Box::new(Condition::Config(String::from("beta"))))));
+        assert_eq!(entry.depends, vec![String::from("rom:/hdr/common/common.prc")]);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let (file_map, diagnostics) = parse(&json!({
+            "version": 2,
+            "auto_discover": "yes",
+            "extra": 1,
+            "modules": {
+                "mario": [
+                    { "handler": "prc" },
+                    { "path": 5 },
+                    {
+                        "path": "rom:/hdr/mario/param.prc",
+                        "priority": 1.5,
+                        "optional": "no",
+                        "load_priority": "urgent",
+                        "condition": "sometimes",
+                        "depends": ["rom:/a.prc", 2],
+                        "unknown": true
+                    },
+                    7
+                ],
+                "luigi": "rom:/hdr/luigi/param.prc"
+            }
+        }));
+        let mut paths = diagnostic_paths(&diagnostics);
+        paths.sort();
+        assert_eq!(paths, vec![
+            "$.auto_discover",
+            "$.extra",
+            "$.modules.luigi",
+            "$.modules.mario[0]",
+            "$.modules.mario[1].path",
+            "$.modules.mario[2].condition",
+            "$.modules.mario[2].depends[1]",
+            "$.modules.mario[2].load_priority",
+            "$.modules.mario[2].optional",
+            "$.modules.mario[2].priority",
+            "$.modules.mario[2].unknown",
+            "$.modules.mario[3]"
+        ]);
+        // the valid parts are kept
+        assert_eq!(file_map.modules["mario"].len(), 1);
+        assert_eq!(file_map.modules["mario"][0].depends, vec![String::from("rom:/a.prc")]);
+        assert!(!file_map.modules.contains_key("luigi"));
+    }
+
+    #[test]
+    fn rejects_bad_roots() {
+        assert_eq!(diagnostic_paths(&parse(&json!([])).1), vec!["$"]);
+        assert_eq!(diagnostic_paths(&parse(&json!({ "version": 3, "modules": {} })).1), vec!["$.version"]);
+        assert_eq!(diagnostic_paths(&parse(&json!({ "version": 2 })).1), vec!["$"]);
+        assert_eq!(validate("{").len(), 1);
+        assert!(validate("{\"mario\": []}").is_empty());
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(Condition::parse("feature:debug"), Some(Condition::Feature(String::from("debug"))));
+        assert_eq!(Condition::parse("!!config:a"), Some(Condition::Not(Box::new(Condition::Not(Box::new(Condition::Config(String::from("a"))))))));
+        assert_eq!(Condition::parse("debug"), None);
+        assert!(!Condition::Feature(String::from("not_a_feature")).is_met());
+        set_config("hdrtest_condition", true);
+        assert!(Condition::Config(String::from("hdrtest_condition")).is_met());
+        assert!(!Condition::parse("!config:hdrtest_condition").unwrap().is_met());
+    }
+}
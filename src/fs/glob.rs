@@ -0,0 +1,168 @@
+// Minimal glob matching for file map entries and handler patterns
+// Supported syntax:
+//   `?`  matches any single character other than `/`
+//   `*`  matches any sequence of characters other than `/`
+//   `**` matches any sequence of characters, including `/`. `**/` can also match no directories at all
+use super::{layer, mount, LoadError};
+
+/// Whether `pattern` contains any glob syntax
+pub fn is_pattern(pattern: &str) -> bool {
+    pattern.contains('*') || pattern.contains('?')
+}
+
+/// Whether `path` is matched by `pattern` in its entirety
+pub fn matches(pattern: &str, path: &str) -> bool {
+    matches_bytes(pattern.as_bytes(), path.as_bytes())
+}
+
+fn matches_bytes(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            if rest.first() == Some(&b'/') && matches_bytes(&rest[1..], path) {
+                return true;
+            }
+            (0..=path.len()).any(|idx| matches_bytes(rest, &path[idx..]))
+        },
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for idx in 0..=path.len() {
+                if matches_bytes(rest, &path[idx..]) {
+                    return true;
+                }
+                if idx < path.len() && path[idx] == b'/' {
+                    break;
+                }
+            }
+            false
+        },
+        Some(b'?') => {
+            !path.is_empty() && path[0] != b'/' && matches_bytes(&pattern[1..], &path[1..])
+        },
+        Some(ch) => {
+            !path.is_empty() && path[0] == *ch && matches_bytes(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+/// Expands a path pattern (`rom:/hdr/mario/*.prc`) into every matching file, sorted by path.
+/// Logical patterns (`hdr/mario/*.prc`) are expanded in every layer and return logical paths.
+/// Only the directories below the first segment containing glob syntax are searched.
+pub fn expand(pattern: &str) -> Result<Vec<String>, LoadError> {
+    if !layer::is_logical(pattern) {
+        return expand_full(pattern);
+    }
+    let mut ret = Vec::new();
+    for root in layer::layers().iter() {
+        let full = format!("{}{}", root, pattern.trim_start_matches('/'));
+        for path in expand_full(&full)?.into_iter() {
+            ret.push(String::from(&path[root.len()..]));
+        }
+    }
+    ret.sort();
+    ret.dedup();
+    Ok(ret)
+}
+
+fn expand_full(pattern: &str) -> Result<Vec<String>, LoadError> {
+    let (mount, relative) = mount::resolve(pattern)?;
+    let prefix = &pattern[..pattern.len() - relative.len()];
+    let segments: Vec<&str> = relative.split('/').filter(|x| !x.is_empty()).collect();
+    let literal_count = segments.iter().take_while(|x| !is_pattern(x)).count();
+    let base = segments[..literal_count].join("/");
+    let remaining = &segments[literal_count..];
+    // `**` can match any depth, otherwise we only need to go as deep as the pattern has segments
+    let max_depth = if remaining.iter().any(|x| x.contains("**")) { usize::MAX } else { remaining.len() };
+    let relative_pattern = segments.join("/");
+
+    let mut ret = Vec::new();
+    let mut stack = vec![(base, 0usize)];
+    while let Some((dir, depth)) = stack.pop() {
+        if depth >= max_depth {
+            continue;
+        }
+        let entries = match mount.list_dir(&dir) {
+            Ok(entries) => entries,
+            Err(LoadError::NotFound) => continue,
+            Err(err) => return Err(err)
+        };
+        for entry in entries.into_iter() {
+            let path = if dir.is_empty() { entry.name } else { format!("{}/{}", dir, entry.name) };
+            if entry.is_dir {
+                stack.push((path, depth + 1));
+            } else if matches(&relative_pattern, &path) {
+                ret.push(format!("{}{}", prefix, path));
+            }
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::mount::{register_mount, unregister_mount, tests::temp_dir, HostMount};
+
+    #[test]
+    fn single_segment_wildcards() {
+        assert!(matches("hdr/*.prc", "hdr/param.prc"));
+        assert!(matches("hdr/*.prc", "hdr/.prc"));
+        assert!(!matches("hdr/*.prc", "hdr/mario/param.prc"));
+        assert!(!matches("hdr/*.prc", "hdr/param.prc.bak"));
+        assert!(matches("hdr/param.?rc", "hdr/param.prc"));
+        assert!(!matches("hdr/param?prc", "hdr/param/prc"));
+        assert!(!matches("hdr/?", "hdr/"));
+        assert!(matches("hdr/param.prc", "hdr/param.prc"));
+        assert!(!matches("hdr/param.prc", "hdr/param.prcx"));
+    }
+
+    #[test]
+    fn recursive_wildcards() {
+        assert!(matches("hdr/**", "hdr/a/b/c.prc"));
+        assert!(matches("hdr/**/*.prc", "hdr/a/b/c.prc"));
+        // `**/` can match no directories at all
+        assert!(matches("hdr/**/*.prc", "hdr/c.prc"));
+        assert!(!matches("hdr/**/*.prc", "hdr/a/c.nus3audio"));
+        assert!(matches("**.prc", "hdr/a/c.prc"));
+        assert!(!matches("other/**", "hdr/a"));
+    }
+
+    #[test]
+    fn detects_patterns() {
+        assert!(is_pattern("hdr/*.prc"));
+        assert!(is_pattern("hdr/param.?rc"));
+        assert!(!is_pattern("hdr/param.prc"));
+    }
+
+    #[test]
+    fn expands_on_a_mount() {
+        let root = temp_dir("glob");
+        for file in ["mario/param.prc", "mario/moves/a.prc", "mario/moves/b.prc", "mario/moves/b.txt", "luigi/param.prc"].iter() {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        register_mount("hdrtest-glob:/", HostMount::read_only(&root));
+
+        assert_eq!(expand("hdrtest-glob:/mario/moves/*.prc").unwrap(), vec![
+            String::from("hdrtest-glob:/mario/moves/a.prc"),
+            String::from("hdrtest-glob:/mario/moves/b.prc")
+        ]);
+        assert_eq!(expand("hdrtest-glob:/*/param.prc").unwrap(), vec![
+            String::from("hdrtest-glob:/luigi/param.prc"),
+            String::from("hdrtest-glob:/mario/param.prc")
+        ]);
+        assert_eq!(expand("hdrtest-glob:/mario/**/*.prc").unwrap(), vec![
+            String::from("hdrtest-glob:/mario/moves/a.prc"),
+            String::from("hdrtest-glob:/mario/moves/b.prc"),
+            String::from("hdrtest-glob:/mario/param.prc")
+        ]);
+        // directories that don't exist have nothing in them
+        assert_eq!(expand("hdrtest-glob:/peach/*.prc").unwrap(), Vec::<String>::new());
+        assert_eq!(expand("hdrtest-missing:/*.prc"), Err(LoadError::BadMount(String::from("hdrtest-missing"))));
+
+        unregister_mount("hdrtest-glob:/");
+    }
+}
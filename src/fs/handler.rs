@@ -0,0 +1,331 @@
+// Per-file-type handlers for files loaded through the file map
+// Every file queued by `load_associated_files` is dispatched to the most recently registered handler whose
+// pattern matches its path. Handlers can pair an unload callback with their loads, or register unload actions
+// with `on_unload` while loading, which are run when the file or the NRO that owns it is unloaded again.
+// Stream handlers are handed the opened file instead of its contents, and files only they handle are queued as streams.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::{Mutex, RwLock};
+use lazy_static::lazy_static;
+use super::{glob, LoadError, ReadSeek};
+use crate::debugln;
+
+/// Processes the contents of a loaded file. An `Err` is reported as `LoadError::HandlerRejected`
+pub type LoadHandler = fn(String, Vec<u8>) -> Result<(), String>;
+
+/// Reads what it needs from the opened file, for files too large to hold in memory. An `Err` is reported as
+/// `LoadError::HandlerRejected`, the same as for a `LoadHandler`
+pub type StreamHandler = fn(String, &mut dyn ReadSeek) -> Result<(), String>;
+
+/// Releases whatever the paired `LoadHandler` created for the file
+pub type UnloadHandler = fn(String);
+
+#[derive(Clone, Copy)]
+enum Loader {
+    Buffer(LoadHandler),
+    Stream(StreamHandler)
+}
+
+// What a file is handed to its handler as, converted to whatever the handler takes
+pub(crate) enum Contents {
+    Buffer(Vec<u8>),
+    Stream(Box<dyn ReadSeek>)
+}
+
+struct RegisteredHandler {
+    pattern: String,
+    load: Loader,
+    unload: Option<UnloadHandler>
+}
+
+impl RegisteredHandler {
+    fn matches(&self, path: &str) -> bool {
+        if glob::is_pattern(&self.pattern) || self.pattern.contains('/') {
+            glob::matches(&self.pattern, path)
+        } else {
+            // bare extensions, with or without the leading dot
+            let extension = self.pattern.trim_start_matches('.');
+            path.len() > extension.len()
+                && path.ends_with(extension)
+                && path.as_bytes()[path.len() - extension.len() - 1] == b'.'
+        }
+    }
+}
+
+type UnloadAction = Box<dyn FnOnce() + Send + 'static>;
+
+struct RegisteredAction {
+    // The NRO the file was being loaded for when the action was registered, if any. Several NROs can load the same file
+    owner: Option<String>,
+    // When the action was registered, actions run in the reverse order
+    order: u64,
+    action: UnloadAction
+}
+
+static LOAD_ORDER: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // The owner of the request whose callbacks are running on this thread
+    static CURRENT_OWNER: RefCell<Option<String>> = RefCell::new(None);
+}
+
+lazy_static! {
+    static ref HANDLERS: RwLock<Vec<RegisteredHandler>> = RwLock::new(Vec::new());
+    // Unload actions for every file that registered any, keyed by path
+    static ref LOADED: Mutex<HashMap<String, Vec<RegisteredAction>>> = Mutex::new(HashMap::new());
+}
+
+// Puts the previous owner back when dropped, so that a panicking callback doesn't leave its owner behind on the worker
+struct OwnerGuard(Option<String>);
+
+impl Drop for OwnerGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT_OWNER.with(|x| *x.borrow_mut() = previous);
+    }
+}
+
+// Runs `f` with the owner that unload actions registered from it are attributed to
+pub(crate) fn with_owner<R, F: FnOnce() -> R>(owner: Option<&str>, f: F) -> R {
+    let _guard = OwnerGuard(CURRENT_OWNER.with(|x| x.replace(owner.map(String::from))));
+    f()
+}
+
+// The owner of the request whose callbacks are running on this thread, if any
+pub(crate) fn current_owner() -> Option<String> {
+    CURRENT_OWNER.with(|x| x.borrow().clone())
+}
+
+/// Registers an action which releases something created while loading `path`. Actions run when the file is unloaded
+/// with `unload_file` for its owner, or when the NRO that owns the load unloads, in the reverse order they were registered.
+/// Meant to be called from load handlers and callbacks, which is how the owning NRO is known.
+pub fn on_unload<F: FnOnce() + Send + 'static>(path: &str, action: F) {
+    let owner = current_owner();
+    let order = LOAD_ORDER.fetch_add(1, Ordering::AcqRel);
+    LOADED.lock().entry(String::from(path)).or_insert_with(Vec::new).push(RegisteredAction {
+        owner,
+        order,
+        action: Box::new(action)
+    });
+}
+
+/// Registers a handler for every file whose path matches `pattern`.
+/// `pattern` is either a bare extension (`"prc"`, `".json"`) or a glob over the full path (`"sd:/hdr/**/*.nuanmb"`).
+/// Handlers registered later take priority over earlier ones when both match.
+pub fn register_handler(pattern: &str, load: LoadHandler, unload: Option<UnloadHandler>) {
+    HANDLERS.write().push(RegisteredHandler {
+        pattern: String::from(pattern),
+        load: Loader::Buffer(load),
+        unload
+    });
+}
+
+/// Registers a handler which streams every file whose path matches `pattern`, see `register_handler`
+pub fn register_stream_handler(pattern: &str, load: StreamHandler, unload: Option<UnloadHandler>) {
+    HANDLERS.write().push(RegisteredHandler {
+        pattern: String::from(pattern),
+        load: Loader::Stream(load),
+        unload
+    });
+}
+
+// The handler for `path`, or the one registered with exactly `pattern` regardless of whether it matches the path
+fn find(path: &str, pattern: Option<&str>) -> Option<(Loader, Option<UnloadHandler>)> {
+    HANDLERS.read()
+        .iter()
+        .rev()
+        .find(|x| match pattern {
+            Some(pattern) => x.pattern == pattern,
+            None => x.matches(path)
+        })
+        .map(|x| (x.load, x.unload))
+}
+
+// Whether the file would be dispatched to a stream handler, so that it should be queued as a stream
+pub(crate) fn streams(path: &str, pattern: Option<&str>) -> bool {
+    matches!(find(path, pattern), Some((Loader::Stream(_), _)))
+}
+
+/// Sends the file to the handler registered for it. Files without a handler are skipped
+pub(crate) fn dispatch(path: String, contents: Contents) -> Result<(), LoadError> {
+    match find(&path, None) {
+        Some((load, unload)) => run(load, unload, path, contents),
+        None => {
+            debugln!("[HDR::FileManager] No handler registered for \"{}\" -- skipping.", path);
+            Ok(())
+        }
+    }
+}
+
+/// Sends the file to the handler registered with exactly `pattern`, regardless of whether it matches the path
+pub(crate) fn dispatch_to(pattern: &str, path: String, contents: Contents) -> Result<(), LoadError> {
+    match find(&path, Some(pattern)) {
+        Some((load, unload)) => run(load, unload, path, contents),
+        None => Err(LoadError::HandlerRejected(format!("no handler registered as \"{}\"", pattern)))
+    }
+}
+
+fn run(load: Loader, unload: Option<UnloadHandler>, path: String, contents: Contents) -> Result<(), LoadError> {
+    // Reloading a file replaces what the previous load for the same NRO created. That is only released once the new
+    // load has succeeded, so a reload that fails or panics leaves the previous load in place
+    let reloaded_at = LOAD_ORDER.load(Ordering::Acquire);
+    let result = match (load, contents) {
+        (Loader::Buffer(load), Contents::Buffer(data)) => (load)(path.clone(), data),
+        (Loader::Buffer(load), Contents::Stream(mut stream)) => {
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data)?;
+            (load)(path.clone(), data)
+        },
+        (Loader::Stream(load), Contents::Buffer(data)) => (load)(path.clone(), &mut Cursor::new(data)),
+        (Loader::Stream(load), Contents::Stream(mut stream)) => (load)(path.clone(), stream.as_mut())
+    };
+    result.map_err(LoadError::HandlerRejected)?;
+    if let Some(unload) = unload {
+        let unload_path = path.clone();
+        on_unload(&path, move || (unload)(unload_path));
+    }
+    let previous = take_actions(&path, current_owner().as_deref(), reloaded_at);
+    if !previous.is_empty() {
+        debugln!("[HDR::FileManager] Released the previous load of \"{}\" after reloading it.", path);
+        run_actions(&path, previous);
+    }
+    Ok(())
+}
+
+// Removes the actions registered for `path` while it was loaded for `owner`, before the action numbered `before`
+fn take_actions(path: &str, owner: Option<&str>, before: u64) -> Vec<RegisteredAction> {
+    let mut loaded = LOADED.lock();
+    let actions = match loaded.get_mut(path) {
+        Some(actions) => actions,
+        None => return Vec::new()
+    };
+    let (taken, kept): (Vec<RegisteredAction>, Vec<RegisteredAction>) = actions.drain(..)
+        .partition(|x| x.owner.as_deref() == owner && x.order < before);
+    *actions = kept;
+    if actions.is_empty() {
+        loaded.remove(path);
+    }
+    taken
+}
+
+// Runs the actions most recently registered first. The lock on LOADED must not be held, so that actions can queue or
+// unload other files
+fn run_actions(path: &str, mut actions: Vec<RegisteredAction>) {
+    debugln!("[HDR::FileManager] Unloading \"{}\"", path);
+    actions.sort_by(|a, b| b.order.cmp(&a.order));
+    for action in actions.into_iter() {
+        (action.action)();
+    }
+}
+
+/// Runs the unload actions registered for `path` while it was loaded for the NRO `owner`, or by loads without an owner
+/// when `owner` is `None`. What other NROs that loaded the same file created is left alone.
+/// Returns whether there were any actions to run.
+pub fn unload_file(path: &str, owner: Option<&str>) -> bool {
+    let actions = take_actions(path, owner, u64::MAX);
+    if actions.is_empty() {
+        return false;
+    }
+    run_actions(path, actions);
+    true
+}
+
+// Runs the unload actions of every file loaded for the NRO `module`, most recently loaded first. Actions registered
+// for other NROs that loaded the same files are left alone. Returns how many files were unloaded
+pub(crate) fn unload_owner(module: &str) -> usize {
+    let mut files: Vec<(String, Vec<RegisteredAction>)> = {
+        let paths: Vec<String> = LOADED.lock().iter()
+            .filter(|(_, actions)| actions.iter().any(|x| x.owner.as_deref() == Some(module)))
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.into_iter().map(|path| {
+            let actions = take_actions(&path, Some(module), u64::MAX);
+            (path, actions)
+        }).collect()
+    };
+    // files are unloaded in the reverse order they finished loading
+    files.sort_by_key(|(_, actions)| std::cmp::Reverse(actions.iter().map(|x| x.order).max()));
+    let count = files.len();
+    for (path, actions) in files.into_iter() {
+        run_actions(&path, actions);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        static ref CONVERTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    fn reload_handler(path: String, data: Vec<u8>) -> Result<(), String> {
+        let data = String::from_utf8(data).unwrap();
+        if data == "bad" {
+            return Err(data);
+        }
+        LOG.lock().push(format!("load {}", data));
+        on_unload(&path, move || LOG.lock().push(format!("unload {}", data)));
+        Ok(())
+    }
+
+    #[test]
+    fn reloads_release_the_previous_load_after_succeeding() {
+        register_handler("hdrtest-reload", reload_handler, None);
+        let load = |data: &str| with_owner(Some("hdrtest_reload"), || {
+            dispatch_to("hdrtest-reload", String::from("hdrtest:/reload.txt"), Contents::Buffer(data.as_bytes().to_vec()))
+        });
+        assert_eq!(load("1"), Ok(()));
+        // a failed reload keeps what the previous load created
+        assert!(load("bad").is_err());
+        assert_eq!(*LOG.lock(), vec!["load 1"]);
+        assert_eq!(load("2"), Ok(()));
+        assert_eq!(*LOG.lock(), vec!["load 1", "load 2", "unload 1"]);
+        assert_eq!(unload_owner("hdrtest_reload"), 1);
+        assert_eq!(*LOG.lock(), vec!["load 1", "load 2", "unload 1", "unload 2"]);
+    }
+
+    #[test]
+    fn unloads_files_for_one_owner() {
+        lazy_static! {
+            static ref UNLOADED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+        }
+        let path = "hdrtest:/unload_file.txt";
+        with_owner(Some("hdrtest_first"), || on_unload(path, || UNLOADED.lock().push("first")));
+        with_owner(Some("hdrtest_second"), || on_unload(path, || UNLOADED.lock().push("second")));
+        assert!(unload_file(path, Some("hdrtest_first")));
+        assert!(!unload_file(path, Some("hdrtest_first")));
+        assert_eq!(*UNLOADED.lock(), vec!["first"]);
+        assert!(unload_file(path, Some("hdrtest_second")));
+        assert_eq!(*UNLOADED.lock(), vec!["first", "second"]);
+    }
+
+    fn stream_handler(path: String, stream: &mut dyn ReadSeek) -> Result<(), String> {
+        let mut data = String::new();
+        stream.read_to_string(&mut data).map_err(|err| err.to_string())?;
+        CONVERTED.lock().push(format!("stream {} {}", path, data));
+        Ok(())
+    }
+
+    fn buffer_handler(path: String, data: Vec<u8>) -> Result<(), String> {
+        CONVERTED.lock().push(format!("buffer {} {}", path, String::from_utf8(data).unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn converts_contents_for_the_handler() {
+        register_stream_handler("hdrtest-streamed", stream_handler, None);
+        register_handler("hdrtest-buffered", buffer_handler, None);
+        assert!(streams("hdrtest:/any.txt", Some("hdrtest-streamed")));
+        assert!(!streams("hdrtest:/any.txt", Some("hdrtest-buffered")));
+        let stream = |data: &str| Contents::Stream(Box::new(Cursor::new(data.as_bytes().to_vec())));
+        assert_eq!(dispatch_to("hdrtest-streamed", String::from("a"), Contents::Buffer(b"1".to_vec())), Ok(()));
+        assert_eq!(dispatch_to("hdrtest-streamed", String::from("b"), stream("2")), Ok(()));
+        assert_eq!(dispatch_to("hdrtest-buffered", String::from("c"), stream("3")), Ok(()));
+        assert_eq!(*CONVERTED.lock(), vec!["stream a 1", "stream b 2", "buffer c 3"]);
+    }
+}
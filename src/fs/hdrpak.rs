@@ -0,0 +1,519 @@
+// The hdrpak archive format
+// Bundles a directory tree of data files into a single file, so that one read of the table of contents replaces
+// hundreds of small file lookups. An opened archive is a `Mount`, so it can back a whole tree:
+//   fs::register_mount("pak:/", HdrPak::open("sd:/ultimate/hdr/hdr.hdrpak")?);
+//   fs::add_layer("pak:/");
+//
+// Layout, all integers are little endian:
+//   header:
+//     magic        [u8; 8]   "HDRPAK\0\0"
+//     version      u32       FORMAT_VERSION
+//     entry_count  u32
+//     names_size   u32       size of the name table
+//     padding      u32
+//   table of contents, entry_count entries sorted by hash:
+//     hash         u64       Hash40 of the path relative to the archive root
+//     offset       u64       from the start of the archive
+//     stored_size  u32       size of the blob in the archive
+//     size         u32       size of the file once decompressed
+//     flags        u32       FLAG_COMPRESSED if the blob is lz4 compressed
+//     padding      u32
+//   name table, the path of every entry in the same order as the table of contents:
+//     length       u16
+//     path         [u8; length]   utf-8, relative to the archive root
+//   blobs
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use parking_lot::Mutex;
+use super::{DirEntry, LoadError, Metadata, Mount, ReadSeek};
+use crate::utils::hash40;
+
+pub const MAGIC: [u8; 8] = *b"HDRPAK\0\0";
+pub const FORMAT_VERSION: u32 = 2;
+pub const FLAG_COMPRESSED: u32 = 1;
+
+const HEADER_SIZE: u64 = 0x18;
+const ENTRY_SIZE: u64 = 0x20;
+// lz4 can't compress better than this, so larger sizes can only come from a corrupt archive
+const MAX_COMPRESSION_RATIO: u64 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PakEntry {
+    pub hash: u64,
+    pub offset: u64,
+    pub stored_size: u32,
+    pub size: u32,
+    pub flags: u32
+}
+
+impl PakEntry {
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> std::io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid_data<S: Into<String>>(message: S) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+/// An opened hdrpak archive
+pub struct HdrPak {
+    reader: Mutex<Box<dyn ReadSeek>>,
+    // The size of the archive, nothing is ever read past it
+    len: u64,
+    entries: Vec<PakEntry>,
+    paths: Vec<String>
+}
+
+impl HdrPak {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+
+    pub fn from_reader<R: ReadSeek + 'static>(mut reader: R) -> std::io::Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not an hdrpak archive"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!("unsupported hdrpak version {}", version)));
+        }
+        let count = read_u32(&mut reader)?;
+        let names_size = read_u32(&mut reader)?;
+        let _padding = read_u32(&mut reader)?;
+        // the counts are checked against the archive before anything is allocated for them
+        let tables_end = HEADER_SIZE + ENTRY_SIZE * count as u64 + names_size as u64;
+        if tables_end > len {
+            return Err(invalid_data("hdrpak table of contents is larger than the archive"));
+        }
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let entry = PakEntry {
+                hash: read_u64(&mut reader)?,
+                offset: read_u64(&mut reader)?,
+                stored_size: read_u32(&mut reader)?,
+                size: read_u32(&mut reader)?,
+                flags: read_u32(&mut reader)?
+            };
+            let _padding = read_u32(&mut reader)?;
+            Self::check_entry(&entry, len)?;
+            entries.push(entry);
+        }
+        if entries.windows(2).any(|x| x[0].hash >= x[1].hash) {
+            return Err(invalid_data("hdrpak table of contents is not sorted"));
+        }
+        let mut names = std::io::Cursor::new(vec![0u8; names_size as usize]);
+        reader.read_exact(names.get_mut())?;
+        let mut paths = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let mut path = vec![0u8; read_u16(&mut names)? as usize];
+            names.read_exact(&mut path)?;
+            let path = String::from_utf8(path).map_err(|_| invalid_data("hdrpak path is invalid UTF-8"))?;
+            if hash40(&path) != entry.hash {
+                return Err(invalid_data(format!("hdrpak path \"{}\" does not match its hash", path)));
+            }
+            paths.push(path);
+        }
+        Ok(Self {
+            reader: Mutex::new(Box::new(reader)),
+            len,
+            entries,
+            paths
+        })
+    }
+
+    fn check_entry(entry: &PakEntry, len: u64) -> std::io::Result<()> {
+        if entry.offset.checked_add(entry.stored_size as u64).map_or(true, |end| end > len) {
+            return Err(invalid_data("hdrpak entry is past the end of the archive"));
+        }
+        let max_size = if entry.is_compressed() { entry.stored_size as u64 * MAX_COMPRESSION_RATIO } else { entry.stored_size as u64 };
+        if entry.size as u64 > max_size {
+            return Err(invalid_data("hdrpak entry is larger than its blob can hold"));
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[PakEntry] {
+        &self.entries
+    }
+
+    /// The path of every entry relative to the archive root, in the same order as `entries`
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    pub fn find(&self, hash: u64) -> Option<&PakEntry> {
+        self.entries.binary_search_by_key(&hash, |x| x.hash).ok().map(|idx| &self.entries[idx])
+    }
+
+    /// Reads and, if needed, decompresses the file at `path`
+    pub fn read_path(&self, path: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match self.find(hash40(path.trim_start_matches('/'))) {
+            Some(entry) => self.read_entry(entry).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    pub fn read_entry(&self, entry: &PakEntry) -> std::io::Result<Vec<u8>> {
+        Self::check_entry(entry, self.len)?;
+        let mut stored = vec![0u8; entry.stored_size as usize];
+        {
+            let mut reader = self.reader.lock();
+            reader.seek(SeekFrom::Start(entry.offset))?;
+            reader.read_exact(&mut stored)?;
+        }
+        let data = if entry.is_compressed() {
+            lz4_flex::decompress(&stored, entry.size as usize).map_err(|err| invalid_data(format!("{}", err)))?
+        } else {
+            stored
+        };
+        if data.len() != entry.size as usize {
+            return Err(invalid_data("hdrpak entry has the wrong size"));
+        }
+        Ok(data)
+    }
+
+    // The entries directly inside of the directory at `path`, by name, or `None` if there is no such directory
+    fn dir_entries(&self, path: &str) -> Option<BTreeMap<&str, bool>> {
+        let dir = path.trim_matches('/');
+        let mut ret = BTreeMap::new();
+        for path in self.paths.iter() {
+            let rest = if dir.is_empty() {
+                path.as_str()
+            } else {
+                match path.strip_prefix(dir).and_then(|x| x.strip_prefix('/')) {
+                    Some(rest) => rest,
+                    None => continue
+                }
+            };
+            match rest.find('/') {
+                Some(idx) => ret.insert(&rest[..idx], true),
+                None => ret.insert(rest, false)
+            };
+        }
+        // the root always exists, even in an empty archive
+        if ret.is_empty() && !dir.is_empty() {
+            None
+        } else {
+            Some(ret)
+        }
+    }
+}
+
+impl Mount for HdrPak {
+    fn read(&self, path: &str) -> Result<Vec<u8>, LoadError> {
+        self.read_path(path)?.ok_or(LoadError::NotFound)
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.find(hash40(path.trim_start_matches('/'))).is_some()
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, LoadError> {
+        let entries = self.dir_entries(path).ok_or(LoadError::NotFound)?;
+        Ok(entries.into_iter().map(|(name, is_dir)| DirEntry { name: String::from(name), is_dir }).collect())
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, LoadError> {
+        if let Some(entry) = self.find(hash40(path.trim_start_matches('/'))) {
+            return Ok(Metadata {
+                size: entry.size as u64,
+                is_dir: false,
+                modified: None
+            });
+        }
+        self.dir_entries(path).ok_or(LoadError::NotFound)?;
+        Ok(Metadata {
+            size: 0,
+            is_dir: true,
+            modified: None
+        })
+    }
+}
+
+struct PendingFile {
+    path: String,
+    data: Vec<u8>,
+    compress: bool
+}
+
+// Sizes and counts are stored as u32, anything larger can't be represented in the archive
+fn to_u32(value: usize, what: &str) -> std::io::Result<u32> {
+    u32::try_from(value).map_err(|_| invalid_data(format!("{} is too large for an hdrpak archive", what)))
+}
+
+/// Builds hdrpak archives, meant to be used by the build pipeline
+pub struct HdrPakWriter {
+    files: Vec<PendingFile>
+}
+
+impl Default for HdrPakWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HdrPakWriter {
+    pub fn new() -> Self {
+        Self {
+            files: Vec::new()
+        }
+    }
+
+    /// Adds a file, `path` is relative to the archive root and uses `/` as the separator
+    pub fn add_file<S: Into<String>>(&mut self, path: S, data: Vec<u8>, compress: bool) -> &mut Self {
+        self.files.push(PendingFile {
+            path: path.into(),
+            data,
+            compress
+        });
+        self
+    }
+
+    /// Adds every file below `root`, with paths relative to it
+    pub fn add_dir<P: AsRef<Path>>(&mut self, root: P, compress: bool) -> std::io::Result<&mut Self> {
+        let root = root.as_ref();
+        let mut stack = vec![root.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    stack.push(path);
+                } else {
+                    let relative = path.strip_prefix(root).expect("Walked outside of the archive root.");
+                    let relative = relative.components()
+                        .map(|x| x.as_os_str().to_string_lossy().into_owned())
+                        .collect::<Vec<String>>()
+                        .join("/");
+                    self.add_file(relative, std::fs::read(&path)?, compress);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut blobs = Vec::with_capacity(self.files.len());
+        for file in self.files.iter() {
+            let path = file.path.trim_start_matches('/');
+            if path.len() > u16::MAX as usize {
+                return Err(invalid_data(format!("\"{}\" is too long for an hdrpak path", path)));
+            }
+            let hash = hash40(path);
+            let (stored, flags) = if file.compress {
+                let compressed = lz4_flex::compress(&file.data);
+                // not worth it if it doesn't save anything
+                if compressed.len() < file.data.len() {
+                    (compressed, FLAG_COMPRESSED)
+                } else {
+                    (file.data.clone(), 0)
+                }
+            } else {
+                (file.data.clone(), 0)
+            };
+            let size = to_u32(file.data.len(), &format!("\"{}\"", path))?;
+            to_u32(stored.len(), &format!("\"{}\"", path))?;
+            blobs.push((hash, size, flags, stored, path));
+        }
+        blobs.sort_by_key(|x| x.0);
+        if let Some(x) = blobs.windows(2).find(|x| x[0].0 == x[1].0) {
+            return Err(invalid_data(format!("\"{}\" and \"{}\" have the same hash", x[0].4, x[1].4)));
+        }
+
+        let mut names = Vec::new();
+        for (_, _, _, _, path) in blobs.iter() {
+            names.extend_from_slice(&(path.len() as u16).to_le_bytes());
+            names.extend_from_slice(path.as_bytes());
+        }
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&to_u32(blobs.len(), "the entry count")?.to_le_bytes())?;
+        writer.write_all(&to_u32(names.len(), "the name table")?.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        let mut offset = HEADER_SIZE + ENTRY_SIZE * blobs.len() as u64 + names.len() as u64;
+        for (hash, size, flags, stored, _) in blobs.iter() {
+            writer.write_all(&hash.to_le_bytes())?;
+            writer.write_all(&offset.to_le_bytes())?;
+            writer.write_all(&(stored.len() as u32).to_le_bytes())?;
+            writer.write_all(&size.to_le_bytes())?;
+            writer.write_all(&flags.to_le_bytes())?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            offset += stored.len() as u64;
+        }
+        writer.write_all(&names)?;
+        for (_, _, _, stored, _) in blobs.iter() {
+            writer.write_all(stored)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn archive(writer: &HdrPakWriter) -> Vec<u8> {
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+        data
+    }
+
+    fn open(data: Vec<u8>) -> std::io::Result<HdrPak> {
+        HdrPak::from_reader(Cursor::new(data))
+    }
+
+    fn sample() -> HdrPakWriter {
+        let mut writer = HdrPakWriter::new();
+        writer
+            .add_file("fighter/mario/param.prc", vec![7; 0x1000], true)
+            .add_file("fighter/mario/moves/jab.prc", b"jab".to_vec(), true)
+            .add_file("/common/common.prc", b"common".to_vec(), false);
+        writer
+    }
+
+    fn error_kind<T>(result: std::io::Result<T>) -> std::io::ErrorKind {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err.kind()
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let pak = open(archive(&sample())).unwrap();
+        assert_eq!(pak.entries().len(), 3);
+        // compressible data is compressed, data that doesn't get smaller or wasn't meant to be compressed is stored
+        let param = pak.find(hash40("fighter/mario/param.prc")).unwrap();
+        assert!(param.is_compressed());
+        assert!((param.stored_size as usize) < 0x1000);
+        assert!(!pak.find(hash40("fighter/mario/moves/jab.prc")).unwrap().is_compressed());
+        assert!(!pak.find(hash40("common/common.prc")).unwrap().is_compressed());
+
+        assert_eq!(pak.read_path("fighter/mario/param.prc").unwrap(), Some(vec![7; 0x1000]));
+        assert_eq!(pak.read_path("/fighter/mario/moves/jab.prc").unwrap(), Some(b"jab".to_vec()));
+        assert_eq!(pak.read_path("common/common.prc").unwrap(), Some(b"common".to_vec()));
+        assert_eq!(pak.read_path("common/missing.prc").unwrap(), None);
+        for (entry, path) in pak.entries().iter().zip(pak.paths().iter()) {
+            assert_eq!(entry.hash, hash40(path));
+        }
+    }
+
+    #[test]
+    fn mounts() {
+        let pak = open(archive(&sample())).unwrap();
+        assert_eq!(pak.read("fighter/mario/moves/jab.prc"), Ok(b"jab".to_vec()));
+        assert_eq!(pak.read("fighter/luigi/param.prc"), Err(LoadError::NotFound));
+        assert!(pak.is_file("common/common.prc"));
+        assert!(!pak.is_file("common"));
+
+        let metadata = pak.metadata("fighter/mario/param.prc").unwrap();
+        assert_eq!((metadata.size, metadata.is_dir), (0x1000, false));
+        assert!(pak.metadata("fighter/mario").unwrap().is_dir);
+        assert!(pak.metadata("/").unwrap().is_dir);
+        assert_eq!(pak.metadata("fighter/luigi"), Err(LoadError::NotFound));
+
+        let names = |path: &str| -> Vec<(String, bool)> {
+            pak.list_dir(path).unwrap().into_iter().map(|x| (x.name, x.is_dir)).collect()
+        };
+        assert_eq!(names(""), vec![(String::from("common"), true), (String::from("fighter"), true)]);
+        assert_eq!(names("fighter/mario/"), vec![(String::from("moves"), true), (String::from("param.prc"), false)]);
+        assert_eq!(names("/fighter/mario/moves"), vec![(String::from("jab.prc"), false)]);
+        assert_eq!(pak.list_dir("fighter/mar"), Err(LoadError::NotFound));
+        assert_eq!(open(archive(&HdrPakWriter::new())).unwrap().list_dir(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_sizes_past_u32() {
+        assert_eq!(to_u32(u32::MAX as usize, "x").unwrap(), u32::MAX);
+        assert_eq!(error_kind(to_u32(u32::MAX as usize + 1, "x")), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_duplicate_hashes() {
+        let mut writer = HdrPakWriter::new();
+        writer.add_file("a.prc", b"a".to_vec(), false).add_file("/a.prc", b"b".to_vec(), false);
+        assert_eq!(error_kind(writer.write(&mut Vec::new())), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut data = archive(&sample());
+        data[0] = b'X';
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+
+        let mut data = archive(&sample());
+        data[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+
+        assert_eq!(error_kind(open(MAGIC.to_vec())), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_unsorted_tables() {
+        let mut data = archive(&sample());
+        // swap the first two entries of the table of contents
+        let first = HEADER_SIZE as usize;
+        let second = first + ENTRY_SIZE as usize;
+        let entry: Vec<u8> = data[first..second].to_vec();
+        data.copy_within(second..second + ENTRY_SIZE as usize, first);
+        data[second..second + ENTRY_SIZE as usize].copy_from_slice(&entry);
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_entries() {
+        // an entry count far larger than the archive fails before anything is allocated for it
+        let mut data = archive(&sample());
+        data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+
+        let mut data = archive(&sample());
+        data[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+
+        // offset, then stored size, of the first entry
+        let offset = HEADER_SIZE as usize + 8;
+        let mut data = archive(&sample());
+        data[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+
+        let mut data = archive(&sample());
+        data[offset + 8..offset + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(error_kind(open(data)), std::io::ErrorKind::InvalidData);
+
+        let pak = open(archive(&sample())).unwrap();
+        let mut entry = pak.entries()[0];
+        entry.offset = pak.len;
+        assert_eq!(error_kind(pak.read_entry(&entry)), std::io::ErrorKind::InvalidData);
+        let mut entry = pak.entries()[0];
+        entry.size = u32::MAX;
+        assert_eq!(error_kind(pak.read_entry(&entry)), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,111 @@
+// Layered resolution of logical paths
+// A path without a mount prefix (`hdr/fighter/mario/param.prc`) is a logical path. It is resolved against an ordered
+// list of layers, each of which is a full path to a directory (`rom:/`, `sd:/`, `sd:/hdr-addons/my_addon/`), and the
+// highest priority layer which has the file wins. This lets small tweaks be shipped on top of HDR without touching
+// the file map or the romfs.
+use std::collections::HashMap;
+use std::sync::Arc;
+use parking_lot::{Mutex, RwLock};
+use lazy_static::lazy_static;
+use super::{mount::{self, Mount}, LoadError};
+
+lazy_static! {
+    // Ordered from lowest to highest priority
+    static ref LAYERS: RwLock<Vec<String>> = RwLock::new(vec![
+        String::from("rom:/"),
+        String::from("sd:/")
+    ]);
+    // The full path every logical path resolved to the last time it was resolved
+    static ref RESOLVED: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+fn normalize_root(root: &str) -> String {
+    if root.ends_with('/') {
+        String::from(root)
+    } else {
+        format!("{}/", root)
+    }
+}
+
+/// Whether `path` is a logical path, which is resolved through the layers instead of a single mount
+pub fn is_logical(path: &str) -> bool {
+    !path.contains(":/")
+}
+
+/// Strips the mount prefix from a full path, logical paths are returned as they are
+pub fn logical_path(path: &str) -> &str {
+    match path.find(":/") {
+        Some(idx) => &path[idx + 2..],
+        None => path
+    }
+}
+
+/// Adds a layer with a higher priority than every existing layer. `root` is a full path such as `"sd:/hdr-addons/my_addon/"`
+pub fn add_layer(root: &str) {
+    let root = normalize_root(root);
+    let mut layers = LAYERS.write();
+    layers.retain(|x| *x != root);
+    layers.push(root);
+}
+
+/// Removes a layer, returning whether it existed
+pub fn remove_layer(root: &str) -> bool {
+    let root = normalize_root(root);
+    let mut layers = LAYERS.write();
+    let len = layers.len();
+    layers.retain(|x| *x != root);
+    layers.len() != len
+}
+
+/// Every layer, ordered from lowest to highest priority
+pub fn layers() -> Vec<String> {
+    LAYERS.read().clone()
+}
+
+/// Resolves a logical path to the full path of the file in the highest priority layer that has it, and remembers the
+/// result for `winning_layer`. Meant for loads, use `find` to only look the file up
+pub fn resolve(path: &str) -> Option<String> {
+    let ret = find(path);
+    match &ret {
+        Some(full) => RESOLVED.lock().insert(String::from(path), full.clone()),
+        None => RESOLVED.lock().remove(path)
+    };
+    ret
+}
+
+/// Same as `resolve`, but without touching what `winning_layer` and `resolved_paths` report
+pub fn find(path: &str) -> Option<String> {
+    let layers = LAYERS.read().clone();
+    for root in layers.iter().rev() {
+        let full = format!("{}{}", root, path.trim_start_matches('/'));
+        if let Ok((mount, relative)) = mount::resolve(&full) {
+            if mount.is_file(&relative) {
+                return Some(full);
+            }
+        }
+    }
+    None
+}
+
+/// The layer root that the logical path resolved to the last time it was loaded
+pub fn winning_layer(path: &str) -> Option<String> {
+    let full = RESOLVED.lock().get(path).cloned()?;
+    LAYERS.read().iter().rev().find(|root| full.starts_with(root.as_str())).cloned()
+}
+
+/// Every logical path that has been resolved so far along with the full path that won, for debugging overrides
+pub fn resolved_paths() -> Vec<(String, String)> {
+    let mut ret: Vec<(String, String)> = RESOLVED.lock().iter().map(|(x, y)| (x.clone(), y.clone())).collect();
+    ret.sort();
+    ret
+}
+
+/// Resolves any path, logical or full, to the mount it is read from and the path relative to that mount
+pub fn resolve_mount(path: &str) -> Result<(Arc<dyn Mount>, String), LoadError> {
+    if is_logical(path) {
+        let full = resolve(path).ok_or(LoadError::NotFound)?;
+        mount::resolve(&full)
+    } else {
+        mount::resolve(path)
+    }
+}
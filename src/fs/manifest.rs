@@ -0,0 +1,307 @@
+// Content integrity manifest
+// An optional json file next to the file map which lists the expected size and crc32 of HDR's data files:
+//   {
+//       "version": 1,
+//       "files": {
+//           "rom:/hdr/common/common.prc": { "size": 1234, "crc32": 3735928559 }
+//       }
+//   }
+// Files listed in the manifest are verified after being read and before they reach their handler, so that
+// corrupt or half-copied files are rejected with an error instead of being parsed. Paths can be full or logical.
+// Streamed files are hashed as they are read instead, see `VerifyingReader`.
+// A manifest that can't be parsed is ignored with a warning, it never keeps files from loading.
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use parking_lot::{Mutex, RwLock};
+use lazy_static::lazy_static;
+use serde_json::Value;
+use super::{layer, LoadError, ReadSeek};
+
+pub const MANIFEST_PATH: &'static str = "rom:/hdr/manifest.json";
+pub const MANIFEST_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub crc32: u32
+}
+
+impl ManifestEntry {
+    /// The entry describing `data`, for generating manifests
+    pub fn compute(data: &[u8]) -> Self {
+        Self {
+            size: data.len() as u64,
+            crc32: crc32fast::hash(data)
+        }
+    }
+}
+
+lazy_static! {
+    static ref MANIFEST: RwLock<HashMap<String, ManifestEntry>> = RwLock::new(HashMap::new());
+}
+
+// The manifest entry for `path`, if the manifest lists it
+pub(crate) fn expected(path: &str) -> Option<ManifestEntry> {
+    let manifest = MANIFEST.read();
+    manifest.get(path).or_else(|| manifest.get(layer::logical_path(path))).copied()
+}
+
+fn check(expected: ManifestEntry, size: u64, crc32: impl FnOnce() -> u32) -> Result<(), LoadError> {
+    // the size is free to check, so only hash files which have the right size
+    if expected.size != size {
+        return Err(LoadError::IntegrityMismatch {
+            expected_size: expected.size,
+            size,
+            expected_crc32: expected.crc32,
+            crc32: 0
+        });
+    }
+    let crc32 = crc32();
+    if crc32 != expected.crc32 {
+        return Err(LoadError::IntegrityMismatch {
+            expected_size: expected.size,
+            size,
+            expected_crc32: expected.crc32,
+            crc32
+        });
+    }
+    Ok(())
+}
+
+/// Checks `data` against the manifest entry for `path`. Files the manifest does not list always pass
+pub fn verify(path: &str, data: &[u8]) -> Result<(), LoadError> {
+    match expected(path) {
+        Some(expected) => check(expected, data.len() as u64, || crc32fast::hash(data)),
+        None => Ok(())
+    }
+}
+
+/// Checks everything `reader` has left against the manifest entry for `path`, a chunk at a time.
+/// Files the manifest does not list always pass, without reading anything
+pub fn verify_stream<R: Read + ?Sized>(path: &str, reader: &mut R) -> Result<(), LoadError> {
+    let expected = match expected(path) {
+        Some(expected) => expected,
+        None => return Ok(())
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    let mut size = 0u64;
+    let mut chunk = vec![0u8; 0x1_0000];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(count) => {
+                hasher.update(&chunk[..count]);
+                size += count as u64;
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err.into())
+        }
+    }
+    check(expected, size, || hasher.finalize())
+}
+
+/// Hashes a stream as it is read front to back, so that streamed files are verified without holding them in memory.
+/// Reaching the end of a file that doesn't match fails that read, and the mismatch is stored in `outcome`.
+/// Reads after a seek away from the hashed part are passed through without hashing, the file has to be verified
+/// with `verify_stream` afterwards when `outcome` is still empty.
+pub(crate) struct VerifyingReader {
+    inner: Box<dyn ReadSeek>,
+    expected: ManifestEntry,
+    hasher: crc32fast::Hasher,
+    // how much of the start of the file has been hashed
+    hashed: u64,
+    position: u64,
+    outcome: Arc<Mutex<Option<Result<(), LoadError>>>>
+}
+
+impl VerifyingReader {
+    pub(crate) fn new(inner: Box<dyn ReadSeek>, expected: ManifestEntry, outcome: Arc<Mutex<Option<Result<(), LoadError>>>>) -> Self {
+        Self {
+            inner,
+            expected,
+            hasher: crc32fast::Hasher::new(),
+            hashed: 0,
+            position: 0,
+            outcome
+        }
+    }
+}
+
+impl Read for VerifyingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if self.position == self.hashed {
+            self.hasher.update(&buf[..count]);
+            self.hashed += count as u64;
+        }
+        self.position += count as u64;
+        if count == 0 && !buf.is_empty() && self.position == self.hashed {
+            let mut outcome = self.outcome.lock();
+            if outcome.is_none() {
+                let hasher = self.hasher.clone();
+                *outcome = Some(check(self.expected, self.hashed, || hasher.finalize()));
+            }
+            if let Some(Err(err)) = outcome.as_ref() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()));
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl Seek for VerifyingReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+fn parse(json: &Value) -> Result<HashMap<String, ManifestEntry>, String> {
+    match json.get("version").and_then(|x| x.as_u64()) {
+        Some(MANIFEST_VERSION) => {},
+        _ => return Err(format!("manifest version must be {}", MANIFEST_VERSION))
+    }
+    let files = json.get("files").and_then(|x| x.as_object()).ok_or_else(|| String::from("manifest is missing \"files\""))?;
+    let mut ret = HashMap::with_capacity(files.len());
+    for (path, entry) in files.iter() {
+        let size = entry.get("size").and_then(|x| x.as_u64());
+        let crc = entry.get("crc32").and_then(|x| x.as_u64()).filter(|x| *x <= u32::MAX as u64);
+        match (size, crc) {
+            (Some(size), Some(crc)) => {
+                ret.insert(path.clone(), ManifestEntry { size, crc32: crc as u32 });
+            },
+            _ => return Err(format!("manifest entry for \"{}\" needs an integer \"size\" and \"crc32\"", path))
+        }
+    }
+    Ok(ret)
+}
+
+fn parse_manifest(data: &[u8]) -> Result<HashMap<String, ManifestEntry>, String> {
+    let json: Value = serde_json::from_slice(data).map_err(|err| format!("Unable to parse manifest: {}", err))?;
+    parse(&json)
+}
+
+// Files are only checked when they are loaded, so a broken install shows up as the affected files failing to load
+pub(crate) fn handle_load_manifest(path: String, data: Result<Vec<u8>, LoadError>) -> Result<(), LoadError> {
+    assert!(path == MANIFEST_PATH);
+    let data = match data {
+        // the manifest is optional
+        Err(LoadError::NotFound) => return Ok(()),
+        data => data
+    };
+    // The manifest only adds checks, so one that can't be used must not keep the data it describes from loading
+    match data.map_err(|err| err.to_string()).and_then(|data| parse_manifest(&data)) {
+        Ok(parsed) => {
+            println!("[HDR::FileManager] Loaded manifest with {} files.", parsed.len());
+            *MANIFEST.write() = parsed;
+        },
+        Err(err) => {
+            println!("[HDR::FileManager] Warning: ignoring the manifest, files will not be verified. {}", err);
+            MANIFEST.write().clear();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn computes_crc32() {
+        assert_eq!(ManifestEntry::compute(b"123456789"), ManifestEntry { size: 9, crc32: 0xCBF43926 });
+        assert_eq!(ManifestEntry::compute(b""), ManifestEntry { size: 0, crc32: 0 });
+    }
+
+    #[test]
+    fn verifies_listed_files() {
+        let data = b"hdr-core manifest test";
+        let entry = ManifestEntry::compute(data);
+        MANIFEST.write().insert(String::from("hdrtest:/manifest/full.bin"), entry);
+        MANIFEST.write().insert(String::from("manifest/logical.bin"), entry);
+
+        assert_eq!(verify("hdrtest:/manifest/full.bin", data), Ok(()));
+        // logical entries apply to the file in every layer
+        assert_eq!(verify("sd:/manifest/logical.bin", data), Ok(()));
+        assert_eq!(verify("manifest/logical.bin", data), Ok(()));
+        // unlisted files always pass
+        assert_eq!(verify("hdrtest:/manifest/unlisted.bin", b"anything"), Ok(()));
+
+        assert_eq!(verify("hdrtest:/manifest/full.bin", &data[1..]), Err(LoadError::IntegrityMismatch {
+            expected_size: entry.size,
+            size: entry.size - 1,
+            expected_crc32: entry.crc32,
+            crc32: 0
+        }));
+        let mut corrupt = data.to_vec();
+        corrupt[0] ^= 1;
+        assert_eq!(verify("hdrtest:/manifest/full.bin", &corrupt), Err(LoadError::IntegrityMismatch {
+            expected_size: entry.size,
+            size: entry.size,
+            expected_crc32: entry.crc32,
+            crc32: crc32fast::hash(&corrupt)
+        }));
+    }
+
+    #[test]
+    fn verifies_streams_as_they_are_read() {
+        let data = b"hdr-core streamed manifest test";
+        let entry = ManifestEntry::compute(data);
+        let read = |data: &[u8]| {
+            let outcome = Arc::new(Mutex::new(None));
+            let mut reader = VerifyingReader::new(Box::new(std::io::Cursor::new(data.to_vec())), entry, outcome.clone());
+            let mut chunk = [0u8; 4];
+            let result = loop {
+                match reader.read(&mut chunk) {
+                    Ok(0) => break Ok(()),
+                    Ok(_) => {},
+                    Err(err) => break Err(err.kind())
+                }
+            };
+            let outcome = outcome.lock().take();
+            (result, outcome)
+        };
+        assert_eq!(read(data), (Ok(()), Some(Ok(()))));
+        let mut corrupt = data.to_vec();
+        corrupt[5] ^= 1;
+        assert_eq!(read(&corrupt), (Err(std::io::ErrorKind::InvalidData), Some(Err(LoadError::IntegrityMismatch {
+            expected_size: entry.size,
+            size: entry.size,
+            expected_crc32: entry.crc32,
+            crc32: crc32fast::hash(&corrupt)
+        }))));
+
+        // skipping ahead leaves the outcome open, so the file has to be verified separately
+        let outcome = Arc::new(Mutex::new(None));
+        let mut reader = VerifyingReader::new(Box::new(std::io::Cursor::new(data.to_vec())), entry, outcome.clone());
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(*outcome.lock(), None);
+
+        MANIFEST.write().insert(String::from("hdrtest:/manifest/streamed.bin"), entry);
+        assert_eq!(verify_stream("hdrtest:/manifest/streamed.bin", &mut &data[..]), Ok(()));
+        assert!(verify_stream("hdrtest:/manifest/streamed.bin", &mut &corrupt[..]).is_err());
+        assert_eq!(verify_stream("hdrtest:/manifest/unlisted.bin", &mut &corrupt[..]), Ok(()));
+    }
+
+    #[test]
+    fn parses_entries() {
+        let parsed = parse(&json!({
+            "version": 1,
+            "files": { "rom:/hdr/common/common.prc": { "size": 4, "crc32": 3735928559u64 } }
+        })).unwrap();
+        assert_eq!(parsed["rom:/hdr/common/common.prc"], ManifestEntry { size: 4, crc32: 0xDEADBEEF });
+        assert!(parse(&json!({ "version": 2, "files": {} })).is_err());
+        assert!(parse(&json!({ "version": 1 })).is_err());
+        assert!(parse(&json!({ "version": 1, "files": { "a": { "size": 1, "crc32": 0x1_0000_0000u64 } } })).is_err());
+    }
+
+    #[test]
+    fn rejects_unparseable_manifests() {
+        assert!(parse_manifest(b"{ not json").is_err());
+        assert!(parse_manifest(br#"{ "version": 1, "files": {} }"#).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1534 @@
+// HDR's filesystem module
+use skyline::{libc, nro::NroInfo};
+use std::collections::{VecDeque, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, Condvar};
+use std::sync::Arc;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::sync::{Once, atomic::*};
+use lazy_static::lazy_static;
+use super::{c_str, debugln};
+
+pub mod arc;
+pub mod cache;
+pub mod file_map;
+pub mod glob;
+pub mod handler;
+pub mod hdrpak;
+pub mod layer;
+pub mod manifest;
+pub mod mount;
+pub mod query;
+pub mod stats;
+pub mod watch;
+
+pub use arc::{ArcMount, ArcSource};
+pub use cache::{set_cache_budget, clear_cache};
+pub use file_map::{FileMapEntry, Diagnostic, set_config};
+pub use hdrpak::{HdrPak, HdrPakWriter};
+pub use handler::{LoadHandler, StreamHandler, UnloadHandler, register_handler, register_stream_handler, on_unload, unload_file};
+pub use layer::{add_layer, remove_layer, winning_layer, resolved_paths};
+pub use mount::{Mount, HostMount, DirEntry, Metadata, ReadSeek, register_mount, unregister_mount};
+pub use query::{list_dir, exists, metadata};
+pub use stats::{Stats, FileTiming, set_trace};
+pub use watch::{enable_hot_reload, disable_hot_reload};
+
+/// Reasons a requested file could not be delivered to (or was refused by) its callback
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// The path does not start with a known mount name, contains the offending mount
+    BadMount(String),
+    /// The path has a valid mount but no file exists there
+    NotFound,
+    /// The file exists but reading it failed
+    Io(std::io::ErrorKind),
+    /// The file was read successfully but the handler could not make use of it
+    HandlerRejected(String),
+    /// The request was removed from the queue before it was processed
+    Cancelled,
+    /// A file this request depends on could not be loaded, or was never queued, contains the path of that file
+    DependencyFailed(String),
+    /// The request is part of a dependency cycle, so it can never be processed
+    DependencyCycle,
+    /// The mount the path belongs to can't be written to
+    ReadOnly,
+    /// The file does not match its entry in the integrity manifest
+    IntegrityMismatch {
+        expected_size: u64,
+        size: u64,
+        expected_crc32: u32,
+        crc32: u32
+    },
+    /// The buffer given to `read_into` can't hold the file, contains the size of the file
+    BufferTooSmall(u64),
+    /// A callback panicked while processing the request, contains the panic message
+    Panicked(String),
+    /// Writes need a full path, since it is ambiguous which layer a logical path should be written to. Contains the path
+    LogicalWrite(String)
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::BadMount(mount) => write!(f, "mount name \"{}\" is invalid", mount),
+            LoadError::NotFound => write!(f, "file not found"),
+            LoadError::Io(kind) => write!(f, "I/O error ({:?})", kind),
+            LoadError::HandlerRejected(reason) => write!(f, "rejected by handler: {}", reason),
+            LoadError::Cancelled => write!(f, "cancelled"),
+            LoadError::DependencyFailed(path) => write!(f, "dependency \"{}\" failed to load", path),
+            LoadError::DependencyCycle => write!(f, "dependency cycle detected"),
+            LoadError::ReadOnly => write!(f, "mount is read-only"),
+            LoadError::IntegrityMismatch { expected_size, size, expected_crc32, crc32 } => {
+                if expected_size != size {
+                    write!(f, "integrity check failed (expected {} bytes, found {})", expected_size, size)
+                } else {
+                    write!(f, "integrity check failed (expected crc32 {:#010x}, found {:#010x})", expected_crc32, crc32)
+                }
+            },
+            LoadError::BufferTooSmall(size) => write!(f, "buffer is too small for the file ({} bytes)", size),
+            LoadError::Panicked(message) => write!(f, "callback panicked: {}", message),
+            LoadError::LogicalWrite(path) => write!(f, "\"{}\" is a logical path, writes need a mount", path)
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => LoadError::NotFound,
+            kind => LoadError::Io(kind)
+        }
+    }
+}
+
+/// Called on a worker thread once a request has been processed, with either the file contents or the reason it could not be read.
+/// The returned result is what gets reported for the request, so handlers should return `LoadError::HandlerRejected` for data they cannot use.
+/// Plain functions work as well as closures, which can capture whatever state they need to store the file.
+pub type LoadCallback = Box<dyn FnOnce(String, Result<Vec<u8>, LoadError>) -> Result<(), LoadError> + Send + 'static>;
+
+/// How urgently a request should be processed. Ready requests are always taken in this order, and in the order they
+/// were queued within the same class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LoadPriority {
+    /// Needed before the game can continue, such as the file map
+    Critical,
+    Normal,
+    /// Loads which can happen while a match is running, such as hot reloads. Background reads are limited to one at a
+    /// time and to the byte budget set with `set_background_budget`, so they can't starve the game of I/O
+    Background
+}
+
+impl Default for LoadPriority {
+    fn default() -> Self {
+        LoadPriority::Normal
+    }
+}
+
+pub struct LoadRequest {
+    pub path: String,
+    /// Every callback that wants the contents of `path`, they all share a single read of the file
+    pub callbacks: Vec<LoadCallback>,
+    /// The name of the NRO this request was made for, used to cancel it when that NRO unloads and to attribute the
+    /// unload actions its callbacks register
+    pub owner: Option<String>,
+    /// Paths that have to be loaded successfully before this request is processed
+    pub depends: Vec<String>,
+    pub priority: LoadPriority
+}
+
+impl LoadRequest {
+    pub fn new<S, F>(path: S, callback: F) -> Self
+    where
+        S: Into<String>,
+        F: FnOnce(String, Result<Vec<u8>, LoadError>) -> Result<(), LoadError> + Send + 'static
+    {
+        LoadRequest {
+            path: path.into(),
+            callbacks: vec![Box::new(callback)],
+            owner: None,
+            depends: Vec::new(),
+            priority: LoadPriority::Normal
+        }
+    }
+
+    /// Adds another callback which receives its own copy of the file once it has been read
+    pub fn with_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(String, Result<Vec<u8>, LoadError>) -> Result<(), LoadError> + Send + 'static
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    pub fn with_owner<S: Into<String>>(mut self, owner: S) -> Self {
+        self.owner = Some(owner.into());
+        self
+    }
+
+    pub fn with_dependencies<S: Into<String>, I: IntoIterator<Item = S>>(mut self, depends: I) -> Self {
+        self.depends.extend(depends.into_iter().map(|x| x.into()));
+        self
+    }
+
+    pub fn with_priority(mut self, priority: LoadPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+// A load request once it is in the queue. Requests for the same path from different NROs are merged into one, so
+// every callback keeps the owner of the request it came from
+struct QueuedLoad {
+    path: String,
+    callbacks: Vec<(Option<String>, LoadCallback)>,
+    depends: Vec<String>,
+    priority: LoadPriority
+}
+
+impl QueuedLoad {
+    fn new(req: LoadRequest) -> Self {
+        let LoadRequest { path, callbacks, owner, depends, priority } = req;
+        Self {
+            path,
+            callbacks: callbacks.into_iter().map(|callback| (owner.clone(), callback)).collect(),
+            depends,
+            priority
+        }
+    }
+
+    // Folds another request for the same path into this one, so that the file is only read once
+    fn merge(&mut self, other: LoadRequest) {
+        let other = Self::new(other);
+        self.callbacks.extend(other.callbacks.into_iter());
+        for dependency in other.depends.into_iter() {
+            if !self.depends.contains(&dependency) {
+                self.depends.push(dependency);
+            }
+        }
+        // the most urgent of the two wins
+        self.priority = self.priority.min(other.priority);
+    }
+}
+
+/// Called on a worker thread once a write has finished, with the reason it failed if it did
+pub type WriteCallback = Box<dyn FnOnce(String, Result<(), LoadError>) + Send + 'static>;
+
+pub struct WriteRequest {
+    pub path: String,
+    pub data: Vec<u8>,
+    pub callback: Option<WriteCallback>
+}
+
+impl WriteRequest {
+    /// `path` has to be a full path, logical paths are rejected since it is ambiguous which layer they should go to
+    pub fn new<S: Into<String>>(path: S, data: Vec<u8>) -> Self {
+        WriteRequest {
+            path: path.into(),
+            data,
+            callback: None
+        }
+    }
+
+    pub fn with_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(String, Result<(), LoadError>) + Send + 'static
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+}
+
+/// Called on a worker thread with the opened file, or the reason it could not be opened. The file is read as the
+/// callback consumes it, so it never has to be held in memory all at once.
+pub type StreamCallback = Box<dyn FnOnce(String, Result<Box<dyn ReadSeek>, LoadError>) -> Result<(), LoadError> + Send + 'static>;
+
+/// A request for a file which is handed to its callback as a stream instead of a buffer, for files which are too
+/// large to comfortably keep two copies of. Streamed files bypass the content cache, which needs the whole file in
+/// memory. Files listed in the integrity manifest are hashed as they are read, and reading the end of one that doesn't
+/// match fails. If the callback doesn't read the file to the end, it is read again afterwards to verify it, and a
+/// mismatch becomes the result of the request.
+pub struct StreamRequest {
+    pub path: String,
+    pub callback: StreamCallback,
+    pub owner: Option<String>,
+    pub depends: Vec<String>,
+    pub priority: LoadPriority
+}
+
+impl StreamRequest {
+    pub fn new<S, F>(path: S, callback: F) -> Self
+    where
+        S: Into<String>,
+        F: FnOnce(String, Result<Box<dyn ReadSeek>, LoadError>) -> Result<(), LoadError> + Send + 'static
+    {
+        StreamRequest {
+            path: path.into(),
+            callback: Box::new(callback),
+            owner: None,
+            depends: Vec::new(),
+            priority: LoadPriority::Normal
+        }
+    }
+
+    /// Hands the file to `callback` in chunks of `chunk_size` bytes, in order. Only the last chunk can be shorter.
+    /// Errors from opening or reading the file, as well as the first error returned by `callback`, become the result of the request.
+    pub fn chunked<S, F>(path: S, chunk_size: usize, mut callback: F) -> Self
+    where
+        S: Into<String>,
+        F: FnMut(&str, &[u8]) -> Result<(), LoadError> + Send + 'static
+    {
+        Self::new(path, move |path, stream| {
+            let mut stream = stream?;
+            let mut chunk = vec![0u8; chunk_size.max(1)];
+            loop {
+                let mut filled = 0;
+                while filled < chunk.len() {
+                    match stream.read(&mut chunk[filled..]) {
+                        Ok(0) => break,
+                        Ok(count) => filled += count,
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                        Err(err) => return Err(err.into())
+                    }
+                }
+                if filled == 0 {
+                    return Ok(());
+                }
+                callback(&path, &chunk[..filled])?;
+                if filled < chunk.len() {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    pub fn with_owner<S: Into<String>>(mut self, owner: S) -> Self {
+        self.owner = Some(owner.into());
+        self
+    }
+
+    /// See `LoadRequest::with_dependencies`
+    pub fn with_dependencies<I, S>(mut self, depends: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>
+    {
+        self.depends.extend(depends.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_priority(mut self, priority: LoadPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+// Counts the bytes read through a stream, so that streamed reads show up in the stats and the background budget
+struct CountingReader {
+    inner: Box<dyn ReadSeek>,
+    count: Arc<AtomicUsize>
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.count.fetch_add(count, Ordering::AcqRel);
+        Ok(count)
+    }
+}
+
+impl Seek for CountingReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+// A callback taken off of the queue by a cancellation, which still has to be told about it
+enum DroppedCallback {
+    Load(LoadCallback),
+    Stream(StreamCallback)
+}
+
+enum Job {
+    Load(QueuedLoad),
+    Write(WriteRequest),
+    Stream(StreamRequest)
+}
+
+impl Job {
+    fn path(&self) -> &str {
+        match self {
+            Job::Load(req) => &req.path,
+            Job::Write(req) => &req.path,
+            Job::Stream(req) => &req.path
+        }
+    }
+
+    fn owners(&self) -> Vec<Option<&str>> {
+        match self {
+            Job::Load(req) => req.callbacks.iter().map(|(owner, _)| owner.as_deref()).collect(),
+            Job::Write(_) => vec![None],
+            Job::Stream(req) => vec![req.owner.as_deref()]
+        }
+    }
+
+    fn depends(&self) -> &[String] {
+        match self {
+            Job::Load(req) => &req.depends,
+            Job::Stream(req) => &req.depends,
+            Job::Write(_) => &[]
+        }
+    }
+
+    fn priority(&self) -> LoadPriority {
+        match self {
+            Job::Load(req) => req.priority,
+            Job::Write(_) => LoadPriority::Normal,
+            Job::Stream(req) => req.priority
+        }
+    }
+}
+
+struct LoadState {
+    path: String,
+    // Can be raised while the request is queued, when a more urgent request for the same path is merged into it
+    priority: Mutex<LoadPriority>,
+    result: Mutex<Option<Result<(), LoadError>>>,
+    condvar: Condvar
+}
+
+/// A handle to a queued request that can be used to check on or block until its completion.
+/// Handles are cheap to clone and every clone observes the same request.
+#[derive(Clone)]
+pub struct LoadHandle {
+    state: Arc<LoadState>
+}
+
+impl LoadHandle {
+    fn new(path: &str, priority: LoadPriority) -> Self {
+        Self {
+            state: Arc::new(LoadState {
+                path: String::from(path),
+                priority: Mutex::new(priority),
+                result: Mutex::new(None),
+                condvar: Condvar::new()
+            })
+        }
+    }
+
+    fn complete(&self, result: Result<(), LoadError>) {
+        let mut lock = self.state.result.lock();
+        *lock = Some(result);
+        drop(lock);
+        self.state.condvar.notify_all();
+    }
+
+    pub fn path(&self) -> &str {
+        &self.state.path
+    }
+
+    /// The priority class the request is queued with
+    pub fn priority(&self) -> LoadPriority {
+        *self.state.priority.lock()
+    }
+
+    /// Whether the request has finished, either successfully or not
+    pub fn is_done(&self) -> bool {
+        self.state.result.lock().is_some()
+    }
+
+    /// The result of the request, or `None` if it has not finished yet
+    pub fn result(&self) -> Option<Result<(), LoadError>> {
+        self.state.result.lock().clone()
+    }
+
+    /// Blocks until the request has finished and returns its result
+    pub fn wait(&self) -> Result<(), LoadError> {
+        let mut lock = self.state.result.lock();
+        loop {
+            if let Some(result) = lock.as_ref() {
+                return result.clone();
+            }
+            self.state.condvar.wait(&mut lock);
+        }
+    }
+
+    /// Blocks until the request has finished or the timeout has elapsed, returning `None` on timeout
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<(), LoadError>> {
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    // No deadline (a timeout too large to represent) waits for as long as it takes
+    fn wait_until(&self, deadline: Option<Instant>) -> Option<Result<(), LoadError>> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Some(self.wait())
+        };
+        let mut lock = self.state.result.lock();
+        loop {
+            if let Some(result) = lock.as_ref() {
+                return Some(result.clone());
+            }
+            if self.state.condvar.wait_until(&mut lock, deadline).timed_out() {
+                return lock.clone();
+            }
+        }
+    }
+
+    /// Blocks until every handle has finished or the timeout has elapsed.
+    /// Returns the results in the same order as the handles, or `None` if any of them did not finish in time.
+    pub fn wait_all(handles: &[LoadHandle], timeout: Duration) -> Option<Vec<Result<(), LoadError>>> {
+        let deadline = Instant::now().checked_add(timeout);
+        handles.iter().map(|handle| handle.wait_until(deadline)).collect()
+    }
+}
+
+struct QueuedRequest {
+    job: Job,
+    handle: LoadHandle,
+    // Set when the request is known to be unprocessable before it's taken off the queue
+    error: Option<LoadError>,
+    queued_at: Instant
+}
+
+enum Readiness {
+    Ready,
+    Waiting,
+    Failed(LoadError)
+}
+
+struct InFlightRequest {
+    id: u64,
+    path: String,
+    owners: Vec<Option<String>>,
+    priority: LoadPriority
+}
+
+// Token bucket limiting how many bytes background requests can read, refilled at BACKGROUND_BUDGET bytes per
+// BACKGROUND_INTERVAL_MS. A read is allowed to start as long as there are tokens left and its size is charged once it
+// finishes, so a large file puts the bucket into debt which later background reads have to wait out.
+struct BackgroundBudget {
+    tokens: i64,
+    refilled: Instant
+}
+
+impl BackgroundBudget {
+    fn refill(&mut self) {
+        let (budget, interval) = background_budget();
+        if budget == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let earned = (now - self.refilled).as_nanos() * budget as u128 / interval.as_nanos().max(1);
+        if earned > 0 {
+            self.tokens = (self.tokens as i128 + earned as i128).min(budget as i128) as i64;
+            self.refilled = now;
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        background_budget().0 != 0 && self.tokens <= 0
+    }
+
+    // When enough tokens will have been earned for the next background read to start
+    fn next_refill(&self) -> Instant {
+        let (budget, interval) = background_budget();
+        let deficit = (1 - self.tokens).max(1) as u128;
+        let nanos = deficit * interval.as_nanos() / (budget.max(1) as u128);
+        self.refilled + Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        self.tokens = self.tokens.saturating_sub(bytes as i64);
+    }
+}
+
+struct QueueState {
+    requests: VecDeque<QueuedRequest>,
+    // Requests which have been taken off of the queue by a worker and have not yet finished their callback
+    in_flight: Vec<InFlightRequest>,
+    next_id: u64,
+    // The most recently queued handle for every path, so that code which did not queue a file can still wait on it
+    handles: HashMap<String, LoadHandle>,
+    background: BackgroundBudget,
+    terminate: bool
+}
+
+struct SharedQueue {
+    state: Mutex<QueueState>,
+    condvar: Condvar,
+    // Signaled every time a worker finishes a request, used to wait out in-flight requests on cancellation
+    idle: Condvar
+}
+
+struct FileManager {
+    workers: Vec<thread::JoinHandle<()>>,
+    shared: Arc<SharedQueue>
+}
+
+impl FileManager {
+    pub fn new(worker_count: usize) -> Self {
+        let shared = Arc::new(SharedQueue {
+            state: Mutex::new(QueueState {
+                requests: VecDeque::new(),
+                in_flight: Vec::new(),
+                next_id: 0,
+                handles: HashMap::new(),
+                background: BackgroundBudget {
+                    tokens: background_budget().0 as i64,
+                    refilled: Instant::now()
+                },
+                terminate: false
+            }),
+            condvar: Condvar::new(),
+            idle: Condvar::new()
+        });
+
+        let worker_count = worker_count.max(1);
+        let mut workers = Vec::with_capacity(worker_count);
+        for idx in 0..worker_count {
+            let shared = shared.clone();
+            let worker = thread::Builder::new()
+                .name(format!("HDR::FileManager{}", idx))
+                .spawn(move || Self::worker_loop(shared))
+                .expect("Failed to spawn FileManager worker thread.");
+            workers.push(worker);
+        }
+
+        FileManager {
+            workers,
+            shared
+        }
+    }
+
+    fn worker_loop(shared: Arc<SharedQueue>) {
+        loop {
+            let mut state = shared.state.lock(); // Acquire the queue
+            // Sleep until there is either a request whose dependencies are done or we are told to exit
+            let mut next = None;
+            while !state.terminate {
+                state.background.refill();
+                next = Self::next_request(&state);
+                if next.is_some() {
+                    break;
+                }
+                // Background requests held back by the budget won't be woken up by anything else
+                if state.background.is_exhausted() && state.requests.iter().any(|x| x.job.priority() == LoadPriority::Background) {
+                    let deadline = state.background.next_refill();
+                    shared.condvar.wait_until(&mut state, deadline);
+                } else {
+                    shared.condvar.wait(&mut state);
+                }
+            }
+            if state.terminate {
+                break;
+            }
+            let (idx, error) = next.unwrap();
+            let queued = state.requests.remove(idx).unwrap(); // Guaranteed to exist by next_request
+            // Mark the request as in-flight before releasing the lock so that cancellation can never miss it
+            let id = state.next_id;
+            state.next_id += 1;
+            state.in_flight.push(InFlightRequest {
+                id,
+                path: String::from(queued.job.path()),
+                owners: queued.job.owners().into_iter().map(|x| x.map(String::from)).collect(),
+                priority: queued.job.priority()
+            });
+            drop(state); // free up mutex so other workers can take requests while we read
+            stats::trace(stats::TraceEvent::Start, queued.job.path(), || format!("(waited {:?})", queued.queued_at.elapsed()));
+            let priority = queued.job.priority();
+            let path = String::from(queued.job.path());
+            let queued_at = queued.queued_at;
+            let job = queued.job;
+            // A panicking callback must not take the worker down with it, or leave the request in-flight forever
+            // where it would block cancellation and every waiter on its handle
+            let processed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || match job {
+                Job::Load(req) => Self::process(req, error, queued_at),
+                Job::Write(req) => (Self::process_write(req), 0),
+                Job::Stream(req) => Self::process_stream(req, error, queued_at)
+            }));
+            let (result, bytes_read) = processed.unwrap_or_else(|payload| {
+                let message = payload.downcast_ref::<&str>().map(|x| String::from(*x))
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("unknown panic"));
+                println!("[HDR::FileManager] Callback for \"{}\" panicked: {}", path, message);
+                (Err(LoadError::Panicked(message)), 0)
+            });
+            stats::trace(stats::TraceEvent::Done, &path, || match &result {
+                Ok(_) => format!("(ok, {:?} total)", queued_at.elapsed()),
+                Err(err) => format!("({}, {:?} total)", err, queued_at.elapsed())
+            });
+            queued.handle.complete(result);
+            let mut state = shared.state.lock();
+            state.in_flight.retain(|x| x.id != id);
+            if priority == LoadPriority::Background {
+                state.background.charge(bytes_read);
+            }
+            drop(state);
+            shared.idle.notify_all();
+            // Requests that were waiting on this one might be able to run now
+            shared.condvar.notify_all();
+        }
+    }
+
+    // Reads through the content cache, which is keyed by the full path so that a different layer winning is a miss
+    fn read_file(path: &str) -> Result<Vec<u8>, LoadError> {
+        let full = if layer::is_logical(path) {
+            layer::resolve(path).ok_or(LoadError::NotFound)?
+        } else {
+            String::from(path)
+        };
+        let (mount, relative) = mount::resolve(&full)?;
+        let modified = mount.modified(&relative);
+        if let Some(data) = cache::get(&full, modified) {
+            return Ok(data);
+        }
+        let data = mount.read(&relative)?;
+        cache::insert(&full, modified, &data);
+        Ok(data)
+    }
+
+    // The index of the most urgent queued request that can be taken off the queue, along with the error it failed with if it can't be loaded.
+    // Requests of the same priority are taken in the order they were queued.
+    fn next_request(state: &QueueState) -> Option<(usize, Option<LoadError>)> {
+        let background_allowed = !state.background.is_exhausted()
+            && !state.in_flight.iter().any(|x| x.priority == LoadPriority::Background);
+        let mut next: Option<(usize, LoadPriority, Option<LoadError>)> = None;
+        for (idx, queued) in state.requests.iter().enumerate() {
+            let priority = queued.job.priority();
+            if next.as_ref().map_or(false, |x| x.1 <= priority) {
+                continue;
+            }
+            match Self::readiness(state, idx, queued) {
+                // Failed requests never read anything, so they don't have to wait for the budget
+                Readiness::Failed(err) => next = Some((idx, priority, Some(err))),
+                Readiness::Ready if priority != LoadPriority::Background || background_allowed => next = Some((idx, priority, None)),
+                _ => {}
+            }
+            if next.as_ref().map_or(false, |x| x.1 == LoadPriority::Critical) {
+                break;
+            }
+        }
+        next.map(|(idx, _, error)| (idx, error))
+    }
+
+    fn readiness(state: &QueueState, idx: usize, queued: &QueuedRequest) -> Readiness {
+        if let Some(err) = queued.error.as_ref() {
+            return Readiness::Failed(err.clone());
+        }
+        if let Job::Write(req) = &queued.job {
+            // Writes to the same file have to happen one at a time and in the order they were queued
+            if state.in_flight.iter().any(|x| x.path == req.path) || state.requests.iter().take(idx).any(|x| x.job.path() == req.path) {
+                return Readiness::Waiting;
+            }
+        }
+        for dependency in queued.job.depends().iter() {
+            if state.requests.iter().any(|x| same_file(x.job.path(), dependency)) || state.in_flight.iter().any(|x| same_file(&x.path, dependency)) {
+                return Readiness::Waiting;
+            }
+            // the handle under the exact path if there is one, then any other request for the same file
+            let done = state.handles.get(dependency).into_iter()
+                .chain(state.handles.iter().filter(|(path, _)| same_file(path, dependency)).map(|(_, handle)| handle))
+                .find_map(|x| x.result());
+            match done {
+                Some(Ok(())) => {},
+                // Not queued, not in-flight and not done means it was never queued at all
+                Some(Err(_)) | None => return Readiness::Failed(LoadError::DependencyFailed(dependency.clone()))
+            }
+        }
+        Readiness::Ready
+    }
+
+    // Marks every queued request that can never become ready because of a dependency cycle.
+    // Requests are repeatedly resolved if none of their dependencies are still queued, whatever is left is part of
+    // (or waits on) a cycle.
+    fn mark_cycles(state: &mut QueueState) {
+        let mut unresolved: Vec<usize> = (0..state.requests.len()).filter(|x| state.requests[*x].error.is_none()).collect();
+        loop {
+            let before = unresolved.len();
+            let requests = &state.requests;
+            let pending: Vec<&str> = unresolved.iter().map(|x| requests[*x].job.path()).collect();
+            unresolved.retain(|x| requests[*x].job.depends().iter().any(|dep| pending.iter().any(|path| same_file(path, dep))));
+            if unresolved.len() == before {
+                break;
+            }
+        }
+        for idx in unresolved.into_iter() {
+            println!("[HDR::FileManager] \"{}\" is part of a dependency cycle.", state.requests[idx].job.path());
+            state.requests[idx].error = Some(LoadError::DependencyCycle);
+        }
+    }
+
+    // The result of the request and the amount of bytes that were read for it
+    fn process(req: QueuedLoad, error: Option<LoadError>, queued_at: Instant) -> (Result<(), LoadError>, usize) {
+        let read_start = Instant::now();
+        let data = match error {
+            Some(err) => Err(err),
+            None => Self::read_file(&req.path).and_then(|data| {
+                manifest::verify(&req.path, &data)?;
+                Ok(data)
+            })
+        };
+        let bytes_read = data.as_ref().map_or(0, |x| x.len());
+        let read = read_start.elapsed();
+        let handler_start = Instant::now();
+        let QueuedLoad { path, mut callbacks, .. } = req;
+        // Read errors are reported through the callbacks as well, so that they can decide whether they matter.
+        // Every callback but the last gets a copy of the data, and the first failure is what gets reported for the request
+        let last = callbacks.pop();
+        let mut results: Vec<Result<(), LoadError>> = callbacks.into_iter()
+            .map(|(owner, callback)| handler::with_owner(owner.as_deref(), || callback(path.clone(), data.clone())))
+            .collect();
+        if let Some((owner, callback)) = last {
+            results.push(handler::with_owner(owner.as_deref(), || callback(path.clone(), data)));
+        }
+        let result = results.into_iter().find(|x| x.is_err()).unwrap_or(Ok(()));
+        stats::record(stats::FileTiming {
+            path: path.clone(),
+            bytes: bytes_read,
+            read,
+            handler: handler_start.elapsed(),
+            total: queued_at.elapsed(),
+            succeeded: result.is_ok()
+        });
+        match &result {
+            Ok(_) => debugln!("[HDR::FileManager] Loaded file \"{}\"", path),
+            Err(err) => println!("[HDR::FileManager] Failed to load file \"{}\": {}", path, err)
+        }
+        (result, bytes_read)
+    }
+
+    fn process_stream(req: StreamRequest, error: Option<LoadError>, queued_at: Instant) -> (Result<(), LoadError>, usize) {
+        let open_start = Instant::now();
+        let count = Arc::new(AtomicUsize::new(0));
+        let stream = match error {
+            Some(err) => Err(err),
+            None => open(&req.path)
+        };
+        let expected = manifest::expected(&req.path);
+        let outcome = Arc::new(Mutex::new(None));
+        let stream = stream.map(|inner| {
+            let counted = Box::new(CountingReader { inner, count: count.clone() }) as Box<dyn ReadSeek>;
+            match expected {
+                Some(expected) => Box::new(manifest::VerifyingReader::new(counted, expected, outcome.clone())) as Box<dyn ReadSeek>,
+                None => counted
+            }
+        });
+        let verify = expected.is_some() && stream.is_ok();
+        let read = open_start.elapsed();
+        let handler_start = Instant::now();
+        let StreamRequest { path, callback, owner, .. } = req;
+        let result = handler::with_owner(owner.as_deref(), || callback(path.clone(), stream));
+        let verified = outcome.lock().take();
+        // a mismatch takes precedence over whatever the callback made of the failed read
+        let result = match (result, verified) {
+            (_, Some(Err(err))) => Err(err),
+            (Ok(()), None) if verify => open(&path).and_then(|mut stream| manifest::verify_stream(&path, &mut stream)),
+            (result, _) => result
+        };
+        let bytes_read = count.load(Ordering::Acquire);
+        // reading is interleaved with the callback, so the handler time includes it
+        stats::record(stats::FileTiming {
+            path: path.clone(),
+            bytes: bytes_read,
+            read,
+            handler: handler_start.elapsed(),
+            total: queued_at.elapsed(),
+            succeeded: result.is_ok()
+        });
+        match &result {
+            Ok(_) => debugln!("[HDR::FileManager] Streamed file \"{}\"", path),
+            Err(err) => println!("[HDR::FileManager] Failed to stream file \"{}\": {}", path, err)
+        }
+        (result, bytes_read)
+    }
+
+    fn process_write(req: WriteRequest) -> Result<(), LoadError> {
+        let result = if layer::is_logical(&req.path) {
+            Err(LoadError::LogicalWrite(req.path.clone()))
+        } else {
+            let result = mount::resolve(&req.path).and_then(|(mount, path)| mount.write(&path, &req.data));
+            // after the write, so that a read racing it can't cache the old contents under a modification time that
+            // the write might not have changed
+            cache::invalidate(&req.path);
+            result
+        };
+        match &result {
+            Ok(_) => debugln!("[HDR::FileManager] Wrote file \"{}\"", req.path),
+            Err(err) => println!("[HDR::FileManager] Failed to write file \"{}\": {}", req.path, err)
+        }
+        if let Some(callback) = req.callback {
+            (callback)(req.path, result.clone());
+        }
+        result
+    }
+
+    pub fn queue_stream(&self, request: StreamRequest) -> LoadHandle {
+        let mut state = self.shared.state.lock();
+        stats::trace(stats::TraceEvent::Enqueue, &request.path, || format!("(stream, {:?})", request.priority));
+        let handle = LoadHandle::new(&request.path, request.priority);
+        let has_dependencies = !request.depends.is_empty();
+        state.handles.insert(request.path.clone(), handle.clone());
+        state.requests.push_back(QueuedRequest {
+            job: Job::Stream(request),
+            handle: handle.clone(),
+            error: None,
+            queued_at: Instant::now()
+        });
+        if has_dependencies {
+            Self::mark_cycles(&mut state);
+        }
+        drop(state);
+        self.shared.condvar.notify_one();
+        handle
+    }
+
+    pub fn queue_write(&self, request: WriteRequest) -> LoadHandle {
+        let mut state = self.shared.state.lock();
+        stats::trace(stats::TraceEvent::Enqueue, &request.path, || format!("(write, {} bytes)", request.data.len()));
+        let handle = LoadHandle::new(&request.path, LoadPriority::Normal);
+        state.requests.push_back(QueuedRequest {
+            job: Job::Write(request),
+            handle: handle.clone(),
+            error: None,
+            queued_at: Instant::now()
+        });
+        drop(state);
+        self.shared.condvar.notify_one();
+        handle
+    }
+
+    /// Queues the requests and returns a handle for each of them, in the same order.
+    /// If a request for the same path is still waiting in the queue, the new request is merged into it so that the file
+    /// is only read once, and the handle for that request is returned instead.
+    pub fn queue(&self, requests: Vec<LoadRequest>) -> Vec<LoadHandle> {
+        let mut state = self.shared.state.lock();
+        let mut handles = Vec::with_capacity(requests.len());
+        let mut added = 0;
+        let mut has_dependencies = false;
+        for req in requests.into_iter() {
+            has_dependencies |= !req.depends.is_empty();
+            let queued = state.requests.iter_mut().find_map(|QueuedRequest { job, handle, .. }| match job {
+                Job::Load(queued) if queued.path == req.path => Some((queued, handle.clone())),
+                _ => None
+            });
+            if let Some((queued, handle)) = queued {
+                stats::trace(stats::TraceEvent::Enqueue, &req.path, || String::from("(merged into queued request)"));
+                queued.merge(req);
+                *handle.state.priority.lock() = queued.priority;
+                handles.push(handle);
+                continue;
+            }
+            stats::trace(stats::TraceEvent::Enqueue, &req.path, || format!("({:?})", req.priority));
+            let handle = LoadHandle::new(&req.path, req.priority);
+            state.handles.insert(req.path.clone(), handle.clone());
+            state.requests.push_back(QueuedRequest {
+                job: Job::Load(QueuedLoad::new(req)),
+                handle: handle.clone(),
+                error: None,
+                queued_at: Instant::now()
+            });
+            handles.push(handle);
+            added += 1;
+        }
+        if has_dependencies {
+            Self::mark_cycles(&mut state);
+        }
+        drop(state);
+        // Wake up as many workers as there are new requests, the rest can stay asleep
+        for _ in 0..added {
+            self.shared.condvar.notify_one();
+        }
+        handles
+    }
+
+    pub fn handle(&self, path: &str) -> Option<LoadHandle> {
+        self.shared.state.lock().handles.get(path).cloned()
+    }
+
+    /// Removes every queued request that passes the filter, then blocks until no request passing the filter is in-flight.
+    /// Merged requests only lose the callbacks whose owner passes the filter, and are removed once none are left.
+    /// Every dropped callback is called with `LoadError::Cancelled`, and cancelled requests have their handles completed
+    /// with it. Writes are never cancelled, since that would silently lose data. Returns the amount of requests removed.
+    fn cancel_where<F: Fn(&str, Option<&str>) -> bool>(&self, filter: F) -> usize {
+        let mut state = self.shared.state.lock();
+        let mut cancelled = Vec::new();
+        let mut dropped = Vec::new();
+        let mut kept = VecDeque::with_capacity(state.requests.len());
+        for mut queued in state.requests.drain(..) {
+            let keep = match &mut queued.job {
+                Job::Load(QueuedLoad { path, callbacks, .. }) => {
+                    let (removed, remaining): (Vec<_>, Vec<_>) = callbacks.drain(..)
+                        .partition(|(owner, _)| filter(path, owner.as_deref()));
+                    *callbacks = remaining;
+                    dropped.extend(removed.into_iter().map(|(owner, callback)| (path.clone(), owner, DroppedCallback::Load(callback))));
+                    !callbacks.is_empty()
+                },
+                Job::Write(_) => true,
+                job => !job.owners().into_iter().any(|owner| filter(job.path(), owner))
+            };
+            if keep {
+                kept.push_back(queued);
+                continue;
+            }
+            cancelled.push(queued.handle.clone());
+            if let Job::Stream(req) = queued.job {
+                dropped.push((req.path, req.owner, DroppedCallback::Stream(req.callback)));
+            }
+        }
+        state.requests = kept;
+        while state.in_flight.iter().any(|x| x.owners.iter().any(|owner| filter(&x.path, owner.as_deref()))) {
+            self.shared.idle.wait(&mut state);
+        }
+        drop(state);
+        // Callbacks hear about the cancellation before anyone waiting on the handles does
+        for (path, owner, callback) in dropped.into_iter() {
+            let called = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                handler::with_owner(owner.as_deref(), || match callback {
+                    DroppedCallback::Load(callback) => callback(path.clone(), Err(LoadError::Cancelled)),
+                    DroppedCallback::Stream(callback) => callback(path.clone(), Err(LoadError::Cancelled))
+                })
+            }));
+            if called.is_err() {
+                println!("[HDR::FileManager] Callback for \"{}\" panicked on cancellation.", path);
+            }
+        }
+        for handle in cancelled.iter() {
+            debugln!("[HDR::FileManager] Cancelled load of \"{}\"", handle.path());
+            stats::trace(stats::TraceEvent::Cancel, handle.path(), String::new);
+            handle.complete(Err(LoadError::Cancelled));
+        }
+        cancelled.len()
+    }
+
+    pub fn cancel_path(&self, path: &str) -> usize {
+        self.cancel_where(|x, _| x == path)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = stats::snapshot();
+        let state = self.shared.state.lock();
+        stats.queue_depth = state.requests.len();
+        stats.in_flight = state.in_flight.len();
+        drop(state);
+        let (hits, misses, bytes) = cache::counters();
+        stats.cache_hits = hits;
+        stats.cache_misses = misses;
+        stats.cache_bytes = bytes;
+        stats
+    }
+
+    pub fn cancel_module(&self, module: &str) -> usize {
+        self.cancel_where(|_, owner| owner == Some(module))
+    }
+}
+
+impl Drop for FileManager {
+    fn drop(&mut self) {
+        self.shared.state.lock().terminate = true;
+        self.shared.condvar.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Whether the request for `path` satisfies `dependency`. A logical dependency is satisfied by the file in any layer,
+// while a full path only by that exact file, so files on different mounts never stand in for each other
+fn same_file(path: &str, dependency: &str) -> bool {
+    if layer::is_logical(dependency) {
+        layer::logical_path(path).trim_start_matches('/') == dependency.trim_start_matches('/')
+    } else {
+        path == dependency
+    }
+}
+
+/// How many bytes background requests may read per interval, 0 disables the limit
+static BACKGROUND_BUDGET: AtomicUsize = AtomicUsize::new(DEFAULT_BACKGROUND_BUDGET);
+static BACKGROUND_INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_BACKGROUND_INTERVAL_MS);
+const DEFAULT_BACKGROUND_BUDGET: usize = 0x10_0000;
+const DEFAULT_BACKGROUND_INTERVAL_MS: u64 = 100;
+
+/// Limits background requests to reading `bytes` every `interval`, on top of only one of them running at a time.
+/// A budget of 0 lets background requests read as fast as the other classes.
+pub fn set_background_budget(bytes: usize, interval: Duration) {
+    BACKGROUND_INTERVAL_MS.store((interval.as_millis() as u64).max(1), Ordering::Release);
+    BACKGROUND_BUDGET.store(bytes, Ordering::Release);
+}
+
+fn background_budget() -> (usize, Duration) {
+    (BACKGROUND_BUDGET.load(Ordering::Acquire), Duration::from_millis(BACKGROUND_INTERVAL_MS.load(Ordering::Acquire)))
+}
+
+/// The amount of worker threads the `FileManager` will spawn when it is first used.
+/// Defaults to `DEFAULT_WORKER_COUNT`, and can be changed with `set_worker_count` before `init` is called.
+static WORKER_COUNT: AtomicUsize = AtomicUsize::new(DEFAULT_WORKER_COUNT);
+const DEFAULT_WORKER_COUNT: usize = 4;
+
+/// Sets the amount of worker threads used to load files.
+/// This only has an effect if it is called before `init`, since the workers are spawned on first use.
+pub fn set_worker_count(count: usize) {
+    WORKER_COUNT.store(count, Ordering::Release);
+}
+
+lazy_static! {
+    static ref FILE_MANAGER: FileManager = FileManager::new(WORKER_COUNT.load(Ordering::Acquire));
+    static ref FILE_MAP: Mutex<HashMap<String, Vec<FileMapEntry>>> = Mutex::new(HashMap::new());
+    static ref MODULE_HANDLES: Mutex<HashMap<String, Vec<LoadHandle>>> = Mutex::new(HashMap::new());
+    static ref FILES_TO_ADD: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    pub static ref ADDED_FILES: Mutex<HashMap<u64, (u32, u64)>> = Mutex::new(HashMap::new());
+}
+
+const FILE_MAP_PATH: &'static str = "rom:/hdr/file_map.json";
+// Where files are discovered for an NRO by convention, as DISCOVERY_ROOT/<NRO name>/, when the file map enables it
+const DISCOVERY_ROOT: &'static str = "rom:/hdr/fighter";
+static AUTO_DISCOVER: AtomicBool = AtomicBool::new(false);
+static INIT: Once = Once::new();
+
+pub fn init() {
+    INIT.call_once(|| {
+        #[cfg(feature = "arc_runtime")]
+        register_mount("arc:/", ArcMount::runtime());
+        register_handler("prc", super::modules::param::ParamModule::handle_param_load, None);
+        #[cfg(feature = "arc_runtime")]
+        super::modules::param::ParamModule::queue_vanilla_params();
+        FILE_MANAGER.queue(vec![
+            LoadRequest::new(manifest::MANIFEST_PATH, manifest::handle_load_manifest).with_priority(LoadPriority::Critical),
+            LoadRequest::new(FILE_MAP_PATH, handle_load_file_map).with_priority(LoadPriority::Critical)
+        ]);
+    });
+}
+
+/// Queues the requests on the file manager, see `LoadHandle` for how to wait on them
+pub fn queue(requests: Vec<LoadRequest>) -> Vec<LoadHandle> {
+    FILE_MANAGER.queue(requests)
+}
+
+/// Queues a write on the file manager. The file is written to a temporary file first and then renamed over the
+/// destination, creating any missing directories along the way. Logical paths fail with `LoadError::LogicalWrite`
+pub fn queue_write(request: WriteRequest) -> LoadHandle {
+    FILE_MANAGER.queue_write(request)
+}
+
+/// Queues a streamed read on the file manager, see `StreamRequest`
+pub fn queue_stream(request: StreamRequest) -> LoadHandle {
+    FILE_MANAGER.queue_stream(request)
+}
+
+/// Opens any path, logical or full, for streaming reads on the calling thread
+pub fn open(path: &str) -> Result<Box<dyn ReadSeek>, LoadError> {
+    let (mount, path) = layer::resolve_mount(path)?;
+    mount.open(&path)
+}
+
+/// Reads the file at `path` into `buf` on the calling thread, without an intermediate copy on mounts that support streaming.
+/// Returns the size of the file, which is how much of `buf` was filled, or `LoadError::BufferTooSmall` if it doesn't fit.
+pub fn read_into(path: &str, buf: &mut [u8]) -> Result<usize, LoadError> {
+    let mut stream = open(path)?;
+    let size = stream.seek(SeekFrom::End(0))?;
+    if size > buf.len() as u64 {
+        return Err(LoadError::BufferTooSmall(size));
+    }
+    stream.seek(SeekFrom::Start(0))?;
+    let size = size as usize;
+    stream.read_exact(&mut buf[..size])?;
+    Ok(size)
+}
+
+/// The current state of the file manager along with timings for every file it has loaded
+pub fn stats() -> Stats {
+    FILE_MANAGER.stats()
+}
+
+/// The handle of the most recent request queued for `path`, if there ever was one
+pub fn handle(path: &str) -> Option<LoadHandle> {
+    FILE_MANAGER.handle(path)
+}
+
+/// The handles of the files queued by `load_associated_files` for every NRO whose name passes the filter
+pub fn find_module_handles<F: Fn(&str) -> bool>(filter: F) -> Vec<LoadHandle> {
+    MODULE_HANDLES.lock()
+        .iter()
+        .filter(|(module, _)| filter(module))
+        .flat_map(|(_, handles)| handles.iter().cloned())
+        .collect()
+}
+
+/// Cancels every queued load of `path`, queued writes are left alone. Once this returns, no callback for `path` is running.
+/// Must not be called from inside a load callback for the same path, since that would wait on itself.
+pub fn cancel_path(path: &str) -> usize {
+    FILE_MANAGER.cancel_path(path)
+}
+
+/// Cancels every queued load owned by the NRO `module`. Once this returns, no callback for that NRO's files is running.
+/// Must not be called from inside a load callback owned by the same NRO, since that would wait on itself.
+pub fn cancel_module(module: &str) -> usize {
+    FILE_MANAGER.cancel_module(module)
+}
+
+// Every file that has been successfully loaded for an NRO that is still loaded, along with the name of that NRO
+pub(crate) fn loaded_files() -> Vec<(String, String)> {
+    MODULE_HANDLES.lock()
+        .iter()
+        .flat_map(|(module, handles)| {
+            handles.iter()
+                .filter(|handle| handle.result() == Some(Ok(())))
+                .map(move |handle| (module.clone(), String::from(handle.path())))
+        })
+        .collect()
+}
+
+/// Runs the unload actions of every file loaded for the NRO `module`, in the reverse order the files were loaded.
+/// Pending loads for the NRO should be cancelled with `cancel_module` first.
+pub fn unload_module(module: &str) {
+    MODULE_HANDLES.lock().remove(module);
+    let count = handler::unload_owner(module);
+    if count != 0 {
+        debugln!("[HDR::FileManager] Unloaded {} files for {}", count, module);
+    }
+}
+
+pub fn load_associated_files(info: &NroInfo) {
+    let file_map = FILE_MAP.lock();
+    let name = String::from(info.name);
+    // if name == "common" {
+    //     unsafe {
+    //         let mut to_add = FILES_TO_ADD.lock();
+    //         let mut added_files = ADDED_FILES.lock();
+    //         *added_files = crate::arc_runtime::LoadedTables::add_files(&to_add);
+    //         to_add.clear();
+    //     }
+    // }
+    let mut files = Vec::new();
+    if let Some(entries) = file_map.get(&name) {
+        let mut entries: Vec<&FileMapEntry> = entries.iter().filter(|x| x.is_enabled()).collect();
+        // stable, so entries with the same priority keep the order they were listed in
+        entries.sort_by(|a, b| b.priority.cmp(&a.priority));
+        for entry in entries.into_iter() {
+            let priority = entry.load_priority;
+            if glob::is_pattern(&entry.path) {
+                match glob::expand(&entry.path) {
+                    Ok(paths) => files.extend(paths.into_iter().map(|x| (x, entry.depends.clone(), priority))),
+                    Err(err) => println!("[HDR::FileManager] Failed to expand \"{}\": {}", entry.path, err)
+                }
+            } else {
+                files.push((entry.path.clone(), entry.depends.clone(), priority));
+            }
+        }
+    }
+    drop(file_map);
+    if AUTO_DISCOVER.load(Ordering::Acquire) {
+        let pattern = format!("{}/{}/**", DISCOVERY_ROOT, name);
+        match glob::expand(&pattern) {
+            Ok(paths) => files.extend(paths.into_iter().map(|x| (x, Vec::new(), LoadPriority::Normal))),
+            Err(err) => println!("[HDR::FileManager] Failed to discover files for {}: {}", name, err)
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    files.retain(|(path, _, _)| seen.insert(path.clone()));
+    if !files.is_empty() {
+        // files whose handler streams them are never read into memory
+        let (streamed, loaded): (Vec<_>, Vec<_>) = files.into_iter().partition(|(path, _, _)| {
+            let entry = FILE_MAP.lock().get(&name).and_then(|x| x.iter().find(|x| x.matches(path)).cloned());
+            handler::streams(path, entry.as_ref().and_then(|x| x.handler.as_deref()))
+        });
+        let requests: Vec<LoadRequest> = loaded.into_iter()
+            .map(|(path, depends, priority)| {
+                LoadRequest::new(path, handle_load_file)
+                    .with_owner(name.as_str())
+                    .with_dependencies(depends)
+                    .with_priority(priority)
+            })
+            .collect();
+        let mut handles = FILE_MANAGER.queue(requests);
+        for (path, depends, priority) in streamed.into_iter() {
+            handles.push(FILE_MANAGER.queue_stream(StreamRequest::new(path, handle_stream_file)
+                .with_owner(name.as_str())
+                .with_dependencies(depends)
+                .with_priority(priority)));
+        }
+        MODULE_HANDLES.lock().insert(name, handles);
+    }
+}
+
+// The file map entry for `path` in the list of the NRO that is loading it, since different NROs can list the same
+// file with different options. When the NRO lists the same file more than once the first entry is used.
+// Must be called from a load callback, loads without an owner were not queued from the file map.
+fn find_entry(path: &str) -> Option<FileMapEntry> {
+    let owner = handler::current_owner()?;
+    FILE_MAP.lock()
+        .get(&owner)?
+        .iter()
+        .find(|x| x.matches(path))
+        .cloned()
+}
+
+fn handle_load_file_map(path: String, data: Result<Vec<u8>, LoadError>) -> Result<(), LoadError> {
+    assert!(path == FILE_MAP_PATH);
+    let data = data?;
+    let json = std::str::from_utf8(data.as_slice())
+        .map_err(|_| LoadError::HandlerRejected(String::from("The loaded file map is invalid UTF-8 data.")))?;
+    let json: serde_json::Value = serde_json::from_str(json)
+        .map_err(|err| LoadError::HandlerRejected(format!("Unable to parse file map: {}", err)))?;
+    let (parsed, diagnostics) = file_map::parse(&json);
+    for diagnostic in diagnostics.iter() {
+        println!("[HDR::FileManager] File map error at {} -- skipping.", diagnostic);
+    }
+    if parsed.auto_discover {
+        AUTO_DISCOVER.store(true, Ordering::Release);
+    }
+    let mut file_map = FILE_MAP.lock();
+    for (module, entries) in parsed.modules.into_iter() {
+        for entry in entries.iter() {
+            if entry.path.ends_with(".nuanmb") {
+                let mut to_add = FILES_TO_ADD.lock();
+                to_add.push(entry.path.clone());
+            }
+        }
+        // Check if the file map contains, if not make a new one that we can add to
+        file_map.entry(module).or_insert_with(Vec::new).extend(entries.into_iter());
+    }
+    Ok(())
+}
+
+pub(crate) fn handle_load_file(path: String, data: Result<Vec<u8>, LoadError>) -> Result<(), LoadError> {
+    let entry = find_entry(&path);
+    let data = match data {
+        Err(LoadError::NotFound) if entry.as_ref().map_or(false, |x| x.optional) => {
+            debugln!("[HDR::FileManager] Optional file \"{}\" not found -- skipping.", path);
+            return Ok(());
+        },
+        data => data?
+    };
+    match entry.and_then(|x| x.handler) {
+        Some(handler) => handler::dispatch_to(&handler, path, handler::Contents::Buffer(data)),
+        None => handler::dispatch(path, handler::Contents::Buffer(data))
+    }
+}
+
+// The same as `handle_load_file`, for files whose handler streams them
+pub(crate) fn handle_stream_file(path: String, stream: Result<Box<dyn ReadSeek>, LoadError>) -> Result<(), LoadError> {
+    let entry = find_entry(&path);
+    let stream = match stream {
+        Err(LoadError::NotFound) if entry.as_ref().map_or(false, |x| x.optional) => {
+            debugln!("[HDR::FileManager] Optional file \"{}\" not found -- skipping.", path);
+            return Ok(());
+        },
+        stream => stream?
+    };
+    match entry.and_then(|x| x.handler) {
+        Some(handler) => handler::dispatch_to(&handler, path, handler::Contents::Stream(stream)),
+        None => handler::dispatch(path, handler::Contents::Stream(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_callback(_: String, data: Result<Vec<u8>, LoadError>) -> Result<(), LoadError> {
+        data.map(|_| ())
+    }
+
+    fn test_mount(name: &str, files: &[&str]) -> String {
+        let root = mount::tests::temp_dir(name);
+        for file in files.iter() {
+            std::fs::write(root.join(file), file.as_bytes()).unwrap();
+        }
+        let prefix = format!("hdrtest-{}:/", name);
+        register_mount(&prefix, HostMount::read_only(&root));
+        prefix
+    }
+
+    #[test]
+    fn waits_without_a_deadline_on_huge_timeouts() {
+        let handle = LoadHandle::new("rom:/hdrtest/timeout.txt", LoadPriority::Normal);
+        handle.complete(Ok(()));
+        assert_eq!(handle.wait_timeout(Duration::MAX), Some(Ok(())));
+        assert_eq!(LoadHandle::wait_all(&[handle], Duration::MAX), Some(vec![Ok(())]));
+    }
+
+    #[test]
+    fn records_load_stats() {
+        let prefix = test_mount("stats", &["stats.txt"]);
+        let path = format!("{}stats.txt", prefix);
+        let manager = FileManager::new(1);
+        let before = manager.stats();
+        let handles = manager.queue(vec![LoadRequest::new(path.clone(), |_, _| {
+            std::thread::sleep(Duration::from_millis(5));
+            Ok(())
+        })]);
+        assert_eq!(handles[0].wait(), Ok(()));
+
+        let after = manager.stats();
+        // the counters are shared with every other test, so only check that this load was counted
+        assert!(after.files_loaded > before.files_loaded);
+        assert!(after.bytes_read >= before.bytes_read + "stats.txt".len() as u64);
+        let timing = after.timings.iter().find(|x| x.path == path).unwrap();
+        assert_eq!(timing.bytes, "stats.txt".len());
+        assert!(timing.succeeded);
+        assert!(timing.handler >= Duration::from_millis(5));
+        assert!(timing.total >= timing.handler);
+        assert_eq!(after.queue_depth, 0);
+        unregister_mount(&prefix);
+    }
+
+    #[test]
+    fn streams_in_chunks() {
+        let root = mount::tests::temp_dir("chunks");
+        std::fs::write(root.join("ten.bin"), b"0123456789").unwrap();
+        std::fs::write(root.join("eight.bin"), b"01234567").unwrap();
+        std::fs::write(root.join("empty.bin"), b"").unwrap();
+        let prefix = "hdrtest-chunks:/";
+        register_mount(prefix, HostMount::read_only(&root));
+        let manager = FileManager::new(1);
+        let chunks = |name: &str| {
+            let chunks = Arc::new(Mutex::new(Vec::new()));
+            let pushed = chunks.clone();
+            let handle = manager.queue_stream(StreamRequest::chunked(format!("{}{}", prefix, name), 4, move |_, chunk| {
+                pushed.lock().push(chunk.to_vec());
+                Ok(())
+            }));
+            assert_eq!(handle.wait(), Ok(()));
+            let received = chunks.lock().clone();
+            received
+        };
+        // only the last chunk is short, and a file which fills its last chunk exactly doesn't get an empty one
+        assert_eq!(chunks("ten.bin"), vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]);
+        assert_eq!(chunks("eight.bin"), vec![b"0123".to_vec(), b"4567".to_vec()]);
+        assert!(chunks("empty.bin").is_empty());
+        unregister_mount(prefix);
+    }
+
+    #[test]
+    fn reads_into_buffers() {
+        let prefix = test_mount("into", &["into.txt"]);
+        let path = format!("{}into.txt", prefix);
+        let mut buf = [0u8; 8];
+        assert_eq!(read_into(&path, &mut buf), Ok(8));
+        assert_eq!(&buf, b"into.txt");
+        let mut large = [0u8; 16];
+        assert_eq!(read_into(&path, &mut large), Ok(8));
+        assert_eq!(&large[..8], b"into.txt");
+        let mut small = [0u8; 7];
+        assert_eq!(read_into(&path, &mut small), Err(LoadError::BufferTooSmall(8)));
+        assert_eq!(small, [0u8; 7]);
+        unregister_mount(&prefix);
+    }
+
+    #[test]
+    fn dependencies_match_logical_paths() {
+        let prefix = test_mount("deps", &["a.txt", "b.txt"]);
+        let manager = FileManager::new(1);
+        // queued first, but has to wait for a.txt even though it names it by its logical path
+        let handles = manager.queue(vec![
+            LoadRequest::new(format!("{}b.txt", prefix), read_callback).with_dependencies(vec!["a.txt"]),
+            LoadRequest::new(format!("{}a.txt", prefix), read_callback)
+        ]);
+        assert_eq!(handles[0].wait(), Ok(()));
+        assert_eq!(handles[1].wait(), Ok(()));
+        // the same relative path on another mount is a different file
+        let handles = manager.queue(vec![
+            LoadRequest::new(format!("{}b.txt", prefix), read_callback).with_dependencies(vec!["hdrtest-elsewhere:/a.txt"])
+        ]);
+        assert_eq!(handles[0].wait(), Err(LoadError::DependencyFailed(String::from("hdrtest-elsewhere:/a.txt"))));
+        unregister_mount(&prefix);
+    }
+
+    #[test]
+    fn cycles_match_logical_paths() {
+        let prefix = test_mount("cycles", &["x.txt", "y.txt"]);
+        let manager = FileManager::new(1);
+        let handles = manager.queue(vec![
+            LoadRequest::new(format!("{}x.txt", prefix), read_callback).with_dependencies(vec!["y.txt"]),
+            LoadRequest::new(format!("{}y.txt", prefix), read_callback).with_dependencies(vec![format!("{}x.txt", prefix)])
+        ]);
+        assert_eq!(handles[0].wait(), Err(LoadError::DependencyCycle));
+        assert_eq!(handles[1].wait(), Err(LoadError::DependencyCycle));
+        unregister_mount(&prefix);
+    }
+
+    #[test]
+    fn never_cancels_writes() {
+        let prefix = test_mount("writes", &[]);
+        let path = format!("{}written.txt", prefix);
+        let manager = FileManager::new(1);
+        let write = manager.queue_write(WriteRequest::new(path.clone(), Vec::new()));
+        // whether or not a worker has taken it yet, the write goes ahead
+        assert_eq!(manager.cancel_path(&path), 0);
+        // the mount is read only
+        assert_eq!(write.wait(), Err(LoadError::ReadOnly));
+        unregister_mount(&prefix);
+    }
+
+    #[test]
+    fn merging_raises_the_handle_priority() {
+        let prefix = test_mount("priority", &["p.txt"]);
+        let manager = FileManager::new(1);
+        let path = format!("{}p.txt", prefix);
+        // queued together, so the second request is merged before a worker can take the first
+        let handles = manager.queue(vec![
+            LoadRequest::new(path.clone(), read_callback).with_priority(LoadPriority::Background),
+            LoadRequest::new(path.clone(), read_callback)
+        ]);
+        assert_eq!(handles[0].priority(), LoadPriority::Normal);
+        assert_eq!(handles[0].wait(), Ok(()));
+        let handle = manager.queue(vec![LoadRequest::new(path, read_callback).with_priority(LoadPriority::Background)]).remove(0);
+        assert_eq!(handle.priority(), LoadPriority::Background);
+        assert_eq!(handle.wait(), Ok(()));
+        unregister_mount(&prefix);
+    }
+
+    #[test]
+    fn merged_requests_keep_their_owners() {
+        let prefix = test_mount("owners", &["block.txt", "shared.txt"]);
+        let manager = FileManager::new(1);
+        // keeps the only worker busy so that the requests below stay queued and get merged
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        manager.queue(vec![LoadRequest::new(format!("{}block.txt", prefix), move |_, _| {
+            started_tx.send(()).unwrap();
+            blocked.recv().unwrap();
+            Ok(())
+        })]);
+        started.recv().unwrap();
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let request = |owner: &'static str| {
+            let log = log.clone();
+            LoadRequest::new(format!("{}shared.txt", prefix), move |path, data| {
+                if data == Err(LoadError::Cancelled) {
+                    log.lock().push(format!("cancel {}", owner));
+                    return Ok(());
+                }
+                log.lock().push(format!("load {}", owner));
+                let log = log.clone();
+                handler::on_unload(&path, move || log.lock().push(format!("unload {}", owner)));
+                Ok(())
+            }).with_owner(format!("hdrtest_owner_{}", owner))
+        };
+        let handles = manager.queue(vec![request("a"), request("b"), request("c")]);
+        // only a's callback is dropped, the read is still needed by b and c
+        assert_eq!(manager.cancel_module("hdrtest_owner_a"), 0);
+        // writes are never cancelled, their callback always hears how they went
+        let written = log.clone();
+        let write = manager.queue_write(WriteRequest::new(format!("{}shared.txt", prefix), Vec::new())
+            .with_callback(move |_, result| written.lock().push(format!("write {:?}", result.is_ok()))));
+        assert_eq!(manager.cancel_path(&format!("{}shared.txt", prefix)), 1);
+        release.send(()).unwrap();
+        assert_eq!(handles[2].wait(), Err(LoadError::Cancelled));
+        // the mount is read only
+        assert_eq!(write.wait(), Err(LoadError::ReadOnly));
+
+        assert_eq!(*log.lock(), vec!["cancel a", "cancel b", "cancel c", "write false"]);
+        let handles = manager.queue(vec![request("b"), request("c")]);
+        assert_eq!(handles[1].wait(), Ok(()));
+        unload_module("hdrtest_owner_b");
+        assert_eq!(log.lock()[4..], ["load b", "load c", "unload b"]);
+        unload_module("hdrtest_owner_c");
+        assert_eq!(log.lock().last().map(String::as_str), Some("unload c"));
+        unregister_mount(&prefix);
+    }
+}
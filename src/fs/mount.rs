@@ -0,0 +1,432 @@
+// Mount points for HDR's filesystem module
+// Every path given to the file manager starts with a mount prefix (`rom:/`, `sd:/`, ...) which selects
+// the backend the rest of the path is read from. Backends can be registered at runtime so that downstream
+// plugins can provide their own, or so that the file pipeline can be pointed at a plain directory.
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use parking_lot::{Mutex, RwLock};
+use lazy_static::lazy_static;
+use super::LoadError;
+
+/// A single entry of a directory listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool
+}
+
+/// Information about a file or directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    /// The size of the file in bytes, always 0 for directories
+    pub size: u64,
+    pub is_dir: bool,
+    pub modified: Option<SystemTime>
+}
+
+/// A readable and seekable source, such as an opened file
+pub trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// A backend that files can be read from
+pub trait Mount: Send + Sync {
+    /// Reads the entire file at `path`, which is relative to the root of the mount
+    fn read(&self, path: &str) -> Result<Vec<u8>, LoadError>;
+
+    /// Whether there is a file at `path`, which is relative to the root of the mount
+    fn is_file(&self, path: &str) -> bool;
+
+    /// Opens the file at `path` for streaming reads, which is relative to the root of the mount.
+    /// Backends that can read files piece by piece should override this, by default the whole file is read up front.
+    fn open(&self, path: &str) -> Result<Box<dyn ReadSeek>, LoadError> {
+        Ok(Box::new(Cursor::new(self.read(path)?)))
+    }
+
+    /// The last modification time of the file at `path`, if the backend can track it.
+    /// Files on mounts which return `None` are never hot reloaded.
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        let _ = path;
+        None
+    }
+
+    /// Replaces the file at `path` with `data`, creating it and its directories if needed.
+    /// Backends that can be written to should make sure a failed write never leaves a partially written file behind.
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), LoadError> {
+        let _ = (path, data);
+        Err(LoadError::ReadOnly)
+    }
+
+    /// The entries of the directory at `path`, which is relative to the root of the mount.
+    /// Backends which can't list their contents return nothing, so globs never match on them.
+    fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, LoadError> {
+        let _ = path;
+        Ok(Vec::new())
+    }
+
+    /// Information about the file or directory at `path`, which is relative to the root of the mount.
+    /// By default only files are known about, and their size is found by opening them.
+    fn metadata(&self, path: &str) -> Result<Metadata, LoadError> {
+        if !self.is_file(path) {
+            return Err(LoadError::NotFound);
+        }
+        Ok(Metadata {
+            size: self.open(path)?.seek(SeekFrom::End(0))?,
+            is_dir: false,
+            modified: self.modified(path)
+        })
+    }
+}
+
+/// A mount backed by a directory on the system's filesystem.
+/// The built-in `rom:/` and `sd:/` mounts are host mounts rooted at the console's own mount points.
+/// Writes never leave a partially written file behind. Replacing a file on the Switch goes through a `.bak` copy of the
+/// previous version. If the game closed in the middle of a write, reads fall back to that copy and the next write of
+/// the file restores it, so that reading never renames anything.
+pub struct HostMount {
+    root: PathBuf,
+    read_only: bool,
+    // Held for the whole of a write, recovery included, so that two writes never move the same backup around
+    writes: Mutex<()>
+}
+
+impl HostMount {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            read_only: false,
+            writes: Mutex::new(())
+        }
+    }
+
+    pub fn read_only<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            read_only: true,
+            writes: Mutex::new(())
+        }
+    }
+
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
+    }
+
+    fn system_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    // The system path to read a file from. That is the backup while the file is missing because a replacement of it
+    // is in progress or was interrupted, see `replace_with_backup`
+    fn file_path(&self, path: &str) -> PathBuf {
+        let sys_path = self.system_path(path);
+        if !sys_path.exists() {
+            let backup = with_extension(&sys_path, ".bak");
+            if backup.is_file() {
+                return backup;
+            }
+        }
+        sys_path
+    }
+
+    // Runs `f` on the path `file_path` picked, and on the file itself if a write finished and removed the backup
+    // between picking it and opening it
+    fn read_with<T, F: Fn(&std::path::Path) -> std::io::Result<T>>(&self, path: &str, f: F) -> Result<T, LoadError> {
+        let sys_path = self.file_path(path);
+        if !sys_path.is_file() {
+            return Err(LoadError::NotFound);
+        }
+        match f(&sys_path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && sys_path != self.system_path(path) => {
+                Ok(f(&self.system_path(path))?)
+            },
+            result => Ok(result?)
+        }
+    }
+}
+
+fn with_extension(path: &std::path::Path, extension: &str) -> PathBuf {
+    let mut ret = path.to_path_buf().into_os_string();
+    ret.push(extension);
+    PathBuf::from(ret)
+}
+
+// Moves `tmp` over `dest` on filesystems which refuse to rename over an existing file, the Switch's included.
+// The old file is renamed to a backup first and only removed once the new one is in place, so that `dest` can always
+// be recovered with `recover`, even if the game closes in the middle of this.
+fn replace_with_backup(tmp: &std::path::Path, dest: &std::path::Path) -> std::io::Result<()> {
+    let backup = with_extension(dest, ".bak");
+    std::fs::rename(dest, &backup)?;
+    if let Err(err) = std::fs::rename(tmp, dest) {
+        std::fs::rename(&backup, dest)?;
+        return Err(err);
+    }
+    let _ = std::fs::remove_file(&backup);
+    Ok(())
+}
+
+// Finishes an interrupted `replace_with_backup`. If the new file made it into place the backup is stale, otherwise
+// the backup is the last complete version of the file
+fn recover(dest: &std::path::Path) {
+    let backup = with_extension(dest, ".bak");
+    if !backup.is_file() {
+        return;
+    }
+    let result = if dest.is_file() {
+        std::fs::remove_file(&backup)
+    } else {
+        std::fs::rename(&backup, dest)
+    };
+    if let Err(err) = result {
+        println!("[HDR::FileManager] Failed to recover \"{}\" from its backup: {}", dest.display(), err);
+    }
+}
+
+impl Mount for HostMount {
+    fn read(&self, path: &str) -> Result<Vec<u8>, LoadError> {
+        self.read_with(path, |sys_path| std::fs::read(sys_path))
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.file_path(path).is_file()
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn ReadSeek>, LoadError> {
+        let file = self.read_with(path, |sys_path| std::fs::File::open(sys_path))?;
+        Ok(Box::new(file))
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        std::fs::metadata(self.file_path(path)).and_then(|x| x.modified()).ok()
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), LoadError> {
+        use std::io::Write;
+        if self.read_only {
+            return Err(LoadError::ReadOnly);
+        }
+        let _lock = self.writes.lock();
+        let sys_path = self.system_path(path);
+        recover(&sys_path);
+        if let Some(parent) = sys_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = with_extension(&sys_path, ".tmp");
+        let written = std::fs::File::create(&tmp_path).and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        });
+        if let Err(err) = written {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        let renamed = std::fs::rename(&tmp_path, &sys_path).or_else(|err| {
+            if sys_path.is_file() {
+                replace_with_backup(&tmp_path, &sys_path)
+            } else {
+                Err(err)
+            }
+        });
+        if let Err(err) = renamed {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, LoadError> {
+        let mut ret = Vec::new();
+        for entry in std::fs::read_dir(self.system_path(path))? {
+            let entry = entry?;
+            ret.push(DirEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: entry.file_type()?.is_dir()
+            });
+        }
+        Ok(ret)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, LoadError> {
+        let metadata = std::fs::metadata(self.file_path(path))?;
+        Ok(Metadata {
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            is_dir: metadata.is_dir(),
+            modified: metadata.modified().ok()
+        })
+    }
+}
+
+lazy_static! {
+    static ref MOUNTS: RwLock<Vec<(String, Arc<dyn Mount>)>> = RwLock::new(vec![
+        (String::from("rom:/"), Arc::new(HostMount::read_only("rom:/")) as Arc<dyn Mount>),
+        (String::from("sd:/"), Arc::new(HostMount::new("sd:/")) as Arc<dyn Mount>)
+    ]);
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    let name = prefix.trim_end_matches('/').trim_end_matches(':');
+    format!("{}:/", name)
+}
+
+/// Registers `mount` under `prefix` (for example `"mods:/"`), replacing any mount previously registered under the same prefix
+pub fn register_mount<M: Mount + 'static>(prefix: &str, mount: M) {
+    let prefix = normalize_prefix(prefix);
+    let mount: Arc<dyn Mount> = Arc::new(mount);
+    let mut mounts = MOUNTS.write();
+    if let Some(entry) = mounts.iter_mut().find(|(x, _)| *x == prefix) {
+        entry.1 = mount;
+    } else {
+        mounts.push((prefix, mount));
+    }
+}
+
+/// Removes the mount registered under `prefix`, returning whether there was one
+pub fn unregister_mount(prefix: &str) -> bool {
+    let prefix = normalize_prefix(prefix);
+    let mut mounts = MOUNTS.write();
+    let len = mounts.len();
+    mounts.retain(|(x, _)| *x != prefix);
+    mounts.len() != len
+}
+
+/// Splits a full path into the mount it belongs to and the path relative to that mount
+pub fn resolve(path: &str) -> Result<(Arc<dyn Mount>, String), LoadError> {
+    let mounts = MOUNTS.read();
+    for (prefix, mount) in mounts.iter() {
+        if path.starts_with(prefix.as_str()) {
+            return Ok((mount.clone(), String::from(&path[prefix.len()..])));
+        }
+    }
+    let mount = path.split(":/").next().unwrap_or("");
+    Err(LoadError::BadMount(String::from(mount)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A fresh directory under the system's temp directory, so that the host backend can be tested on any machine
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hdr-core-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn host_mount_reads_and_lists() {
+        let root = temp_dir("host-read");
+        std::fs::create_dir_all(root.join("hdr/mario")).unwrap();
+        std::fs::write(root.join("hdr/mario/param.prc"), b"mario").unwrap();
+        let mount = HostMount::read_only(&root);
+
+        assert_eq!(mount.read("hdr/mario/param.prc"), Ok(b"mario".to_vec()));
+        assert_eq!(mount.read("/hdr/mario/param.prc"), Ok(b"mario".to_vec()));
+        assert_eq!(mount.read("hdr/mario/missing.prc"), Err(LoadError::NotFound));
+        // directories are not files
+        assert_eq!(mount.read("hdr/mario"), Err(LoadError::NotFound));
+        assert!(mount.is_file("hdr/mario/param.prc"));
+        assert!(!mount.is_file("hdr/mario"));
+
+        let mut buf = String::new();
+        mount.open("hdr/mario/param.prc").unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "mario");
+
+        let metadata = mount.metadata("hdr/mario/param.prc").unwrap();
+        assert_eq!((metadata.size, metadata.is_dir), (5, false));
+        assert!(metadata.modified.is_some());
+        assert!(mount.metadata("hdr").unwrap().is_dir);
+        assert_eq!(mount.metadata("nothing"), Err(LoadError::NotFound));
+
+        let mut listing = mount.list_dir("hdr").unwrap();
+        listing.append(&mut mount.list_dir("hdr/mario").unwrap());
+        assert_eq!(listing, vec![
+            DirEntry { name: String::from("mario"), is_dir: true },
+            DirEntry { name: String::from("param.prc"), is_dir: false }
+        ]);
+        assert_eq!(mount.list_dir("nothing"), Err(LoadError::NotFound));
+    }
+
+    #[test]
+    fn host_mount_writes() {
+        let root = temp_dir("host-write");
+        let mount = HostMount::new(&root);
+        mount.write("new/dir/file.bin", b"first").unwrap();
+        assert_eq!(mount.read("new/dir/file.bin"), Ok(b"first".to_vec()));
+        // replacing an existing file leaves nothing else behind
+        mount.write("new/dir/file.bin", b"second").unwrap();
+        assert_eq!(mount.read("new/dir/file.bin"), Ok(b"second".to_vec()));
+        assert_eq!(mount.list_dir("new/dir").unwrap().len(), 1);
+
+        let read_only = HostMount::read_only(&root);
+        assert_eq!(read_only.write("new/dir/file.bin", b"third"), Err(LoadError::ReadOnly));
+        assert_eq!(mount.read("new/dir/file.bin"), Ok(b"second".to_vec()));
+    }
+
+    #[test]
+    fn replaces_through_a_backup() {
+        let root = temp_dir("host-backup");
+        let dest = root.join("file.bin");
+        let tmp = root.join("file.bin.tmp");
+        std::fs::write(&dest, b"old").unwrap();
+        std::fs::write(&tmp, b"new").unwrap();
+        replace_with_backup(&tmp, &dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+        assert!(!tmp.exists());
+        assert!(!root.join("file.bin.bak").exists());
+
+        // a failed rename puts the old file back
+        replace_with_backup(&root.join("missing.tmp"), &dest).unwrap_err();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+        assert!(!root.join("file.bin.bak").exists());
+    }
+
+    #[test]
+    fn recovers_interrupted_writes() {
+        let root = temp_dir("host-recover");
+        let mount = HostMount::new(&root);
+        // interrupted before the new file was in place, the backup is the file until the next write restores it
+        std::fs::write(root.join("a.bin.bak"), b"backup").unwrap();
+        assert_eq!(mount.read("a.bin"), Ok(b"backup".to_vec()));
+        assert!(mount.is_file("a.bin"));
+        assert_eq!(mount.metadata("a.bin").map(|x| x.size), Ok(6));
+        assert!(root.join("a.bin.bak").exists());
+        recover(&root.join("a.bin"));
+        assert_eq!(std::fs::read(root.join("a.bin")).unwrap(), b"backup");
+        assert!(!root.join("a.bin.bak").exists());
+
+        // interrupted after the new file was in place, the backup is stale
+        std::fs::write(root.join("b.bin"), b"new").unwrap();
+        std::fs::write(root.join("b.bin.bak"), b"old").unwrap();
+        assert_eq!(mount.read("b.bin"), Ok(b"new".to_vec()));
+        assert!(root.join("b.bin.bak").exists());
+        mount.write("b.bin", b"newer").unwrap();
+        assert_eq!(mount.read("b.bin"), Ok(b"newer".to_vec()));
+        assert!(!root.join("b.bin.bak").exists());
+
+        // read only mounts read the backup as well, and never touch the files
+        std::fs::write(root.join("c.bin.bak"), b"backup").unwrap();
+        assert_eq!(HostMount::read_only(&root).read("c.bin"), Ok(b"backup".to_vec()));
+        assert!(root.join("c.bin.bak").exists());
+    }
+
+    #[test]
+    fn register_and_resolve() {
+        let root = temp_dir("register");
+        std::fs::write(root.join("file.txt"), b"hello").unwrap();
+        // the prefix is normalized, so all of these name the same mount
+        register_mount("hdrtest-register", HostMount::read_only(&root));
+        register_mount("hdrtest-register:", HostMount::read_only(&root));
+        register_mount("hdrtest-register:/", HostMount::read_only(&root));
+        assert_eq!(MOUNTS.read().iter().filter(|(x, _)| x == "hdrtest-register:/").count(), 1);
+
+        let (mount, relative) = resolve("hdrtest-register:/file.txt").unwrap();
+        assert_eq!(relative, "file.txt");
+        assert_eq!(mount.read(&relative), Ok(b"hello".to_vec()));
+        assert!(resolve("sd:/hdr/file.txt").is_ok());
+
+        assert!(unregister_mount("hdrtest-register"));
+        assert!(!unregister_mount("hdrtest-register"));
+        assert_eq!(resolve("hdrtest-register:/file.txt").err(), Some(LoadError::BadMount(String::from("hdrtest-register"))));
+    }
+}
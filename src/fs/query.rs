@@ -0,0 +1,96 @@
+// Synchronous filesystem queries
+// These go through the same mounts and layers as the file manager, so plugins can find out what files exist without
+// reaching for `std::fs` and skipping over mounts or overrides. Everything here runs on the calling thread and is
+// safe to use from init code, before any worker has started.
+use std::collections::BTreeMap;
+use super::{layer, mount::{self, DirEntry, Metadata}, LoadError};
+
+/// Whether there is a file or directory at `path`, which can be logical or full
+pub fn exists(path: &str) -> bool {
+    metadata(path).is_ok()
+}
+
+/// Information about the file or directory at `path`, which can be logical or full.
+/// For logical paths, files in the highest priority layer win over directories.
+pub fn metadata(path: &str) -> Result<Metadata, LoadError> {
+    if !layer::is_logical(path) {
+        let (mount, relative) = mount::resolve(path)?;
+        return mount.metadata(&relative);
+    }
+    // a query is not a load, so it must not change which layer `layer::winning_layer` reports
+    if let Some(full) = layer::find(path) {
+        let (mount, relative) = mount::resolve(&full)?;
+        return mount.metadata(&relative);
+    }
+    for root in layer::layers().iter().rev() {
+        let full = format!("{}{}", root, path.trim_start_matches('/'));
+        if let Ok((mount, relative)) = mount::resolve(&full) {
+            if let Ok(metadata) = mount.metadata(&relative) {
+                return Ok(metadata);
+            }
+        }
+    }
+    Err(LoadError::NotFound)
+}
+
+/// The entries of the directory at `path`, sorted by name. Logical paths list the directory in every layer, and when
+/// layers disagree on whether an entry is a file or a directory the highest priority layer wins.
+pub fn list_dir(path: &str) -> Result<Vec<DirEntry>, LoadError> {
+    if !layer::is_logical(path) {
+        let (mount, relative) = mount::resolve(path)?;
+        let mut ret = mount.list_dir(&relative)?;
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        return Ok(ret);
+    }
+    let mut found = false;
+    let mut entries = BTreeMap::new();
+    for root in layer::layers().iter() {
+        let full = format!("{}{}", root, path.trim_start_matches('/'));
+        let listing = match mount::resolve(&full) {
+            Ok((mount, relative)) => mount.list_dir(&relative),
+            Err(err) => Err(err)
+        };
+        match listing {
+            Ok(listing) => {
+                found = true;
+                for entry in listing.into_iter() {
+                    entries.insert(entry.name.clone(), entry);
+                }
+            },
+            // a layer not having the directory is normal, anything else is worth knowing about
+            Err(LoadError::NotFound) | Err(LoadError::BadMount(_)) => {},
+            Err(err) => return Err(err)
+        }
+    }
+    if !found {
+        return Err(LoadError::NotFound);
+    }
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mount::{tests::temp_dir, register_mount, unregister_mount, HostMount};
+
+    #[test]
+    fn queries_leave_resolution_alone() {
+        let root = temp_dir("query");
+        std::fs::create_dir_all(root.join("hdrtest_query/dir")).unwrap();
+        std::fs::write(root.join("hdrtest_query/file.txt"), b"query").unwrap();
+        register_mount("hdrtest-query:/", HostMount::read_only(&root));
+        layer::add_layer("hdrtest-query:/");
+
+        assert!(exists("hdrtest_query/file.txt"));
+        assert!(metadata("hdrtest_query/dir").unwrap().is_dir);
+        assert!(!exists("hdrtest_query/missing.txt"));
+        assert_eq!(layer::winning_layer("hdrtest_query/file.txt"), None);
+        assert!(layer::resolved_paths().iter().all(|(path, _)| !path.starts_with("hdrtest_query/")));
+
+        layer::resolve("hdrtest_query/file.txt");
+        assert_eq!(layer::winning_layer("hdrtest_query/file.txt").as_deref(), Some("hdrtest-query:/"));
+
+        layer::remove_layer("hdrtest-query:/");
+        unregister_mount("hdrtest-query:/");
+    }
+}
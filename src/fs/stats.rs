@@ -0,0 +1,114 @@
+// Load statistics and tracing
+// Every processed request records how long its read and its callbacks took, so that the cost of loading HDR's data
+// can be measured on hardware instead of guessed. `fs::stats()` combines these with the current state of the queue.
+// Tracing prints every step of a request's lifecycle to the log, and is off by default since it is very noisy.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use parking_lot::Mutex;
+use lazy_static::lazy_static;
+
+/// Timings of the most recent load of a single file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTiming {
+    pub path: String,
+    pub bytes: usize,
+    /// Time spent reading and verifying the file
+    pub read: Duration,
+    /// Time spent in the request's callbacks
+    pub handler: Duration,
+    /// Time from the request being queued to its callbacks returning, including time spent waiting in the queue
+    pub total: Duration,
+    pub succeeded: bool
+}
+
+/// A snapshot of the file manager, see `fs::stats`
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Requests waiting to be picked up by a worker
+    pub queue_depth: usize,
+    /// Requests a worker is currently processing
+    pub in_flight: usize,
+    /// Bytes read by load requests since startup
+    pub bytes_read: u64,
+    pub files_loaded: u64,
+    pub files_failed: u64,
+    /// Reads served from the content cache without touching the mount
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Bytes currently held by the content cache
+    pub cache_bytes: usize,
+    /// The timings of every file that has been loaded, sorted by path
+    pub timings: Vec<FileTiming>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    Enqueue,
+    Start,
+    Done,
+    Cancel
+}
+
+impl std::fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TraceEvent::Enqueue => write!(f, "enqueue"),
+            TraceEvent::Start => write!(f, "start"),
+            TraceEvent::Done => write!(f, "done"),
+            TraceEvent::Cancel => write!(f, "cancel")
+        }
+    }
+}
+
+static TRACE: AtomicBool = AtomicBool::new(false);
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static FILES_LOADED: AtomicU64 = AtomicU64::new(0);
+static FILES_FAILED: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TIMINGS: Mutex<HashMap<String, FileTiming>> = Mutex::new(HashMap::new());
+}
+
+/// Enables or disables printing every request's lifecycle to the log
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Release);
+}
+
+pub fn is_trace_enabled() -> bool {
+    TRACE.load(Ordering::Acquire)
+}
+
+// `detail` is only evaluated when tracing is enabled
+pub(crate) fn trace<F: FnOnce() -> String>(event: TraceEvent, path: &str, detail: F) {
+    if is_trace_enabled() {
+        println!("[HDR::FileManager] trace: {} \"{}\" {}", event, path, detail());
+    }
+}
+
+pub(crate) fn record(timing: FileTiming) {
+    BYTES_READ.fetch_add(timing.bytes as u64, Ordering::AcqRel);
+    if timing.succeeded {
+        FILES_LOADED.fetch_add(1, Ordering::AcqRel);
+    } else {
+        FILES_FAILED.fetch_add(1, Ordering::AcqRel);
+    }
+    TIMINGS.lock().insert(timing.path.clone(), timing);
+}
+
+// Everything but the queue and cache state, which the file manager fills in
+pub(crate) fn snapshot() -> Stats {
+    let mut timings: Vec<FileTiming> = TIMINGS.lock().values().cloned().collect();
+    timings.sort_by(|a, b| a.path.cmp(&b.path));
+    Stats {
+        queue_depth: 0,
+        in_flight: 0,
+        bytes_read: BYTES_READ.load(Ordering::Acquire),
+        files_loaded: FILES_LOADED.load(Ordering::Acquire),
+        files_failed: FILES_FAILED.load(Ordering::Acquire),
+        cache_hits: 0,
+        cache_misses: 0,
+        cache_bytes: 0,
+        timings
+    }
+}
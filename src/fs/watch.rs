@@ -0,0 +1,93 @@
+// Hot reloading for files loaded through the file map
+// When enabled, a background thread polls the modification time of every file that is currently loaded for an NRO.
+// Files that changed since they were last seen are queued again and re-dispatched to their handler, which is
+// expected to replace whatever it created for the previous version of the file.
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicU64, Ordering};
+use super::{layer, mount, LoadPriority, LoadRequest};
+use crate::debugln;
+
+// Bumped by every enable and disable, so it is odd while hot reloading is enabled. Each watcher thread polls for as
+// long as the generation it was started for is current, which keeps a quick disable and enable from ending up with
+// no watcher or two of them
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL_MS);
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+/// Starts polling the files from the file map for changes every `interval`.
+/// Calling this again while hot reloading is enabled only changes the interval.
+pub fn enable_hot_reload(interval: Duration) {
+    INTERVAL_MS.store((interval.as_millis() as u64).max(1), Ordering::Release);
+    let generation = match bump_generation(1) {
+        Some(generation) => generation,
+        None => return
+    };
+    thread::Builder::new()
+        .name(String::from("HDR::FileWatcher"))
+        .spawn(move || watch_loop(generation))
+        .expect("Failed to spawn file watcher thread.");
+}
+
+/// Stops polling for changes. The watcher thread exits after its current interval
+pub fn disable_hot_reload() {
+    bump_generation(0);
+}
+
+pub fn is_hot_reload_enabled() -> bool {
+    GENERATION.load(Ordering::Acquire) % 2 == 1
+}
+
+// Moves to the next generation if the current one isn't already odd (enabled) or even (disabled) as `parity` asks,
+// returning the new generation
+fn bump_generation(parity: u64) -> Option<u64> {
+    let mut current = GENERATION.load(Ordering::Acquire);
+    loop {
+        if current % 2 == parity {
+            return None;
+        }
+        match GENERATION.compare_exchange_weak(current, current + 1, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return Some(current + 1),
+            Err(actual) => current = actual
+        }
+    }
+}
+
+fn watch_loop(generation: u64) {
+    let mut stamps: HashMap<String, (String, SystemTime)> = HashMap::new();
+    while GENERATION.load(Ordering::Acquire) == generation {
+        for (module, path) in super::loaded_files().into_iter() {
+            // Logical paths are resolved every time, so that adding or removing an override also counts as a change
+            let full = if layer::is_logical(&path) {
+                match layer::resolve(&path) {
+                    Some(full) => full,
+                    None => continue
+                }
+            } else {
+                path.clone()
+            };
+            let modified = match mount::resolve(&full) {
+                Ok((mount, relative)) => mount.modified(&relative),
+                Err(_) => None
+            };
+            let modified = match modified {
+                Some(modified) => (full, modified),
+                None => continue
+            };
+            // The first time we see a file we only record it, it was just loaded by the regular pipeline
+            match stamps.insert(path.clone(), modified.clone()) {
+                Some(previous) if previous != modified => {
+                    debugln!("[HDR::FileWatcher] \"{}\" changed, reloading.", path);
+                    super::queue(vec![
+                        LoadRequest::new(path, super::handle_load_file)
+                            .with_owner(module)
+                            .with_priority(LoadPriority::Background)
+                    ]);
+                },
+                _ => {}
+            }
+        }
+        thread::sleep(Duration::from_millis(INTERVAL_MS.load(Ordering::Acquire)));
+    }
+}
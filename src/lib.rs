@@ -3,7 +3,8 @@
 #![feature(asm)]
 #![feature(new_uninit)]
 #![feature(vec_into_raw_parts)]
-// pub mod arc_runtime;
+#[cfg(feature = "arc_runtime")]
+pub mod arc_runtime;
 pub mod fs;
 pub mod modules;
 pub mod singletons;
@@ -17,7 +18,9 @@ pub fn nro_hook(info: &skyline::nro::NroInfo) {
 }
 
 pub fn nro_unhook(info: &skyline::nro::NroInfo) {
-    modules::ParamModule::handle_param_unload(info);
+    // Make sure nothing for this NRO is still being loaded before releasing what was already loaded
+    fs::cancel_module(info.name);
+    fs::unload_module(info.name);
     // modules::anim::handle_nuanmb_unload(info);
 }
 
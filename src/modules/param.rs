@@ -1,7 +1,8 @@
 use smash::app::BattleObjectModuleAccessor;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use parking_lot::RwLock;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Cursor;
 use lazy_static::lazy_static;
 use super::PARAM_MODULE_OFFSET;
@@ -20,14 +21,16 @@ macro_rules! get_param_module {
 //    for the lifetime of the program that every fighter can reference
 // 2. When a fighter NRO is loaded, we queue the files over the in the `fs` module. We read the
 //    file extension and if it's a `.prc` file we send it to the ParamModule
-// 3. When the ParamModule gets a fighter PRC, we use prc-rs to parse it. For our use case, since we
-//    are just beginning to use custom param files, I (blujay) think it is acceptable to limit
-//    each fighter's params to the highest level (no nested structs, no lists except for the shared fighter params)
+// 3. When the ParamModule gets a fighter PRC, we use prc-rs to parse it. The top level of the file is flattened into
+//    maps so that plain param names are a single hash lookup, and the parsed tree is kept alongside them so that
+//    params nested in structs and lists can be looked up by path ("special_s.charge_frames[2]"). An agent can have
+//    any number of param files, which are merged into a single set of maps and a single tree
 // 4. When the fighter NRO is unloaded, we first remove any potential loads from the queue, since that will block
 //    the calling thread until we know for sure that the files have either been loaded or prevented from being loaded,
-//    and then we signal to ParamModule that it can release the static references to our parsed param data.
+//    and then the file manager runs the unload actions ParamModule registered when it loaded the files, which release
+//    the static references to our parsed param data.
 // Note:
-//    Since I have not yet tested this, I am unsure of how much delay it will cause in-game when loading a match, but it shouldn't
+//    The time spent reading and parsing the param files can be checked with `fs::stats()`. It shouldn't
 //    stall the UI thread since the NRO loading thread is separate from that. Ideally we don't have to implement a separate parsing
 //    thread for the parameter data, but if we need to we can.
 
@@ -46,15 +49,88 @@ lazy_static! {
     static ref AGENT_INT64: RwLock<HashMap<String, Arc<HashMap<u64, u64>>>>  = RwLock::new(HashMap::new());
     static ref AGENT_FLOAT: RwLock<HashMap<String, Arc<HashMap<u64, f32>>>>  = RwLock::new(HashMap::new());
     static ref AGENT_FLAG:  RwLock<HashMap<String, Arc<HashMap<u64, bool>>>> = RwLock::new(HashMap::new());
+
+    // The full parsed files, for params nested inside of structs and lists
+    static ref COMMON_TREE:         RwLock<Option<Arc<prc::ParamStruct>>>          = RwLock::new(None);
+    static ref SHARED_FIGHTER_TREE: RwLock<Option<Arc<prc::ParamStruct>>>          = RwLock::new(None);
+    static ref AGENT_TREE:          RwLock<HashMap<String, Arc<prc::ParamStruct>>> = RwLock::new(HashMap::new());
+
+    // Every param file currently loaded for an agent keyed by path, along with the id of the load that added it.
+    // An agent's maps and tree above are the merge of all of its files.
+    static ref AGENT_FILES: RwLock<HashMap<String, BTreeMap<String, (u64, Arc<prc::ParamStruct>)>>> = RwLock::new(HashMap::new());
+
+    // The top level of the game's common.prc, and of every fighter's entry in its fighter_param.prc by fighter kind
+    static ref VANILLA_COMMON:  RwLock<Option<Arc<HashMap<u64, prc::ParamKind>>>>      = RwLock::new(None);
+    static ref VANILLA_FIGHTER: RwLock<Option<Arc<Vec<HashMap<u64, prc::ParamKind>>>>> = RwLock::new(None);
+}
+
+const COMMON_PRC_PATH: &'static str = "rom:/hdr/common/common.prc";
+const FIGHTER_PARAM_PRC_PATH: &'static str = "rom:/hdr/common/fighter_param.prc";
+// The game's own param tables, which FighterParamAccessor2 holds at runtime. They are read from the arc instead of
+// going through the accessor since the tables are what says whether a key exists at all, where the accessor reads
+// missing keys as 0
+const VANILLA_COMMON_PRC_PATH: &'static str = "arc:/fighter/common/param/common.prc";
+const VANILLA_FIGHTER_PARAM_PRC_PATH: &'static str = "arc:/fighter/common/param/fighter_param.prc";
+const PARAM_LOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// Bumped every time a param file is (re)loaded, so that existing ParamModules know to take the new maps
+static PARAM_GENERATION: AtomicU64 = AtomicU64::new(0);
+// Identifies every load of an agent's param file, so that unloading an old version of a file never removes the new one
+static AGENT_LOAD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A top-level param name hashed ahead of time, so that per-frame lookups don't have to hash the name again.
+/// Build one with `param_key!("name")`, which is hashed at compile time, or `ParamKey::new` in a `const`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ParamKey(pub u64);
+
+impl ParamKey {
+    pub const fn new(name: &str) -> Self {
+        ParamKey(crate::utils::hash40(name))
+    }
+
+    pub const fn from_hash(hash: u64) -> Self {
+        ParamKey(hash)
+    }
+}
+
+/// Hashes a param name into a `ParamKey` at compile time
+#[macro_export]
+macro_rules! param_key {
+    ($name:expr) => {{
+        const KEY: $crate::modules::ParamKey = $crate::modules::ParamKey::new($name);
+        KEY
+    }}
 }
 
 #[derive(Copy, Clone)]
 pub enum ParamType {
     Common,
     Shared,
-    Agent
+    Agent,
+    /// Looks in Agent, then Shared, then Common, and finally in the game's own params, so that HDR's files only have
+    /// to contain the values they change. The vanilla lookup checks the fighter's own params before the common ones,
+    /// see `vanilla_param`. Vanilla params are read from the arc, so without the `arc_runtime` feature keys that none
+    /// of HDR's files have are reported as `ParamLayer::Missing`
+    Resolve
+}
+
+/// The layer a param was read from, see `ParamType::Resolve`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParamLayer {
+    Agent,
+    Shared,
+    Common,
+    Vanilla,
+    /// No layer had the param, so it read as the default value
+    Missing
 }
 
+const RESOLVE_ORDER: [(ParamType, ParamLayer); 3] = [
+    (ParamType::Agent, ParamLayer::Agent),
+    (ParamType::Shared, ParamLayer::Shared),
+    (ParamType::Common, ParamLayer::Common)
+];
+
 pub struct ParamModule {
     common_int: Option<Arc<HashMap<u64, i32>>>,
     common_int64: Option<Arc<HashMap<u64, u64>>>,
@@ -70,15 +146,24 @@ pub struct ParamModule {
     agent_int64: Option<Arc<HashMap<u64, u64>>>,
     agent_float: Option<Arc<HashMap<u64, f32>>>,
     agent_flag: Option<Arc<HashMap<u64, bool>>>,
+
+    common_tree: Option<Arc<prc::ParamStruct>>,
+    // The whole fighter_param.prc, this fighter's params are at index `agent_kind` of its list
+    shared_tree: Option<Arc<prc::ParamStruct>>,
+    agent_tree: Option<Arc<prc::ParamStruct>>,
+
+    category: i32,
+    agent_kind: i32,
+    // The value of PARAM_GENERATION when the maps above were taken
+    generation: u64
 }
 
 impl ParamModule {
-    fn handle_common_prc(obj: &prc::ParamStruct) {
+    fn handle_common_prc(obj: &prc::ParamStruct) -> Result<(), String> {
         let mut int = COMMON_INT.write();
         let mut int64 = COMMON_INT64.write();
         let mut float = COMMON_FLOAT.write();
         let mut flag = COMMON_FLAG.write();
-        assert!(int.is_none() && int64.is_none() && float.is_none() && flag.is_none(), "Error: Common PRC Reloaded");
         let mut int_map = HashMap::<u64, i32>::new();
         let mut int64_map = HashMap::<u64, u64>::new();
         let mut float_map = HashMap::<u64, f32>::new();
@@ -116,8 +201,10 @@ impl ParamModule {
                         let val = if let prc::hash40::Hash40(v) = val { v } else { unreachable!() };
                         int64_map.insert(*hash, *val);
                     },
+                    // nested params are only reachable by path, through the parsed tree
+                    Struct(_) | List(_) => {},
                     _ => {
-                        panic!("Invalid param kind: must be bool, int, int64, or float.");
+                        return Err(String::from("Invalid param kind: must be bool, int, int64, float, struct or list."));
                     }
                 }
             }
@@ -126,18 +213,20 @@ impl ParamModule {
         *int64 = Some(Arc::new(int64_map));
         *float = Some(Arc::new(float_map));
         *flag = Some(Arc::new(flag_map));
+        Ok(())
     }
 
-    fn handle_shared_prc(obj: &prc::ParamStruct) {
+    fn handle_shared_prc(obj: &prc::ParamStruct) -> Result<(), String> {
         let mut int = SHARED_FIGHTER_INT.write();
         let mut int64 = SHARED_FIGHTER_INT64.write();
         let mut float = SHARED_FIGHTER_FLOAT.write();
         let mut flag = SHARED_FIGHTER_FLAG.write();
-        assert!(int.is_none() && int64.is_none() && float.is_none() && flag.is_none(), "Error: Shared fighter PRC reloaded.");
         if let prc::ParamStruct(params) = obj {
             use prc::ParamKind::*;
-            assert!(params.len() == 1, "Error: Shared fighter PRC has the wrong amount of elements.");
-            if let (_, List(list)) = params.get(0).expect("Error: Failed to read parsed PRC data.") {
+            if params.len() != 1 {
+                return Err(String::from("Error: Shared fighter PRC has the wrong amount of elements."));
+            }
+            if let (_, List(list)) = params.get(0).ok_or_else(|| String::from("Error: Failed to read parsed PRC data."))? {
             if let prc::ParamList(list) = list {
                 let sz = list.len();
                 let mut int_vec = Vec::with_capacity(sz);
@@ -182,8 +271,9 @@ impl ParamModule {
                                 let val = if let prc::hash40::Hash40(v) = val { v } else { unreachable!() };
                                 int64_map.insert(*hash, *val);
                             },
+                            Struct(_) | List(_) => {},
                             _ => {
-                                panic!("Invalid param kind: must be bool, int, int64, or float.");
+                                return Err(String::from("Invalid param kind: must be bool, int, int64, float, struct or list."));
                             }
                         }
                     }
@@ -192,302 +282,491 @@ impl ParamModule {
                     float_vec.push(Arc::new(float_map));
                     flag_vec.push(Arc::new(flag_map));
                 } else { unreachable!() }
-                } else { panic!("Error: Malformed shared fighter PRC."); }
+                } else { return Err(String::from("Error: Malformed shared fighter PRC.")); }
                 }
                 *int = Some(int_vec);
                 *int64 = Some(int64_vec);
                 *float = Some(float_vec);
                 *flag = Some(flag_vec);
+                Ok(())
             } else { unreachable!() }
-            } else { panic!("Error: Malformed shared fighter PRC."); }
+            } else { Err(String::from("Error: Malformed shared fighter PRC.")) }
         } else { unreachable!() }
     }
 
-    fn handle_fighter_prc(agent: &String, obj: &prc::ParamStruct) {
-        let mut int = AGENT_INT.write();
-        let mut int64 = AGENT_INT64.write();
-        let mut float = AGENT_FLOAT.write();
-        let mut flag = AGENT_FLAG.write();
-        assert!(!int.contains_key(agent) && !int64.contains_key(agent) && !float.contains_key(agent) && !flag.contains_key(agent),
-            "Error: Unique fighter PRC reloaded while previous is still loaded.");
+    // Adds (or replaces) one of the agent's param files and merges it with the others, returning the id of the load
+    fn handle_fighter_prc(agent: &String, path: &str, obj: prc::ParamStruct) -> Result<u64, String> {
+        let id = AGENT_LOAD_ID.fetch_add(1, Ordering::AcqRel);
+        let mut files = AGENT_FILES.write();
+        let mut agent_files = files.get(agent).cloned().unwrap_or_default();
+        agent_files.insert(String::from(path), (id, Arc::new(obj)));
+        // a file with invalid params leaves the agent's previously merged params untouched
+        Self::merge_fighter_prcs(agent, &agent_files)?;
+        files.insert(agent.clone(), agent_files);
+        Ok(id)
+    }
+
+    // Rebuilds the agent's maps and tree from all of its files. Files are merged in path order, and when more than one
+    // of them has the same top-level param the first one wins
+    fn merge_fighter_prcs(agent: &String, files: &BTreeMap<String, (u64, Arc<prc::ParamStruct>)>) -> Result<(), String> {
         let mut int_map = HashMap::<u64, i32>::new();
         let mut int64_map = HashMap::<u64, u64>::new();
         let mut float_map = HashMap::<u64, f32>::new();
         let mut flag_map = HashMap::<u64, bool>::new();
-        if let prc::ParamStruct(params) = obj {
+        let mut tree = Vec::new();
+        let mut sources = HashMap::<u64, &String>::new();
+        for (path, (_, obj)) in files.iter() {
+            let prc::ParamStruct(params) = obj.as_ref();
             for (hash, value) in params.iter() {
                 use prc::ParamKind::*;
-                let hash = if let prc::hash40::Hash40(val) = hash { val } else { unreachable!() };
+                if let Some(source) = sources.get(&hash.0) {
+                    println!("[HDR::ParamModule] Param {:#x} of {} is in both \"{}\" and \"{}\", using the one from \"{}\".", hash.0, agent, source, path, source);
+                    continue;
+                }
+                sources.insert(hash.0, path);
                 match value {
                     Bool(val) => {
-                        flag_map.insert(*hash, *val);
+                        flag_map.insert(hash.0, *val);
                     },
                     I8(val) => {
-                        int_map.insert(*hash,*val as i32);
+                        int_map.insert(hash.0, *val as i32);
                     },
                     U8(val) => {
-                        int_map.insert(*hash,*val as i32);
+                        int_map.insert(hash.0, *val as i32);
                     },
                     I16(val) => {
-                        int_map.insert(*hash,*val as i32);
+                        int_map.insert(hash.0, *val as i32);
                     },
                     U16(val) => {
-                        int_map.insert(*hash,*val as i32);
+                        int_map.insert(hash.0, *val as i32);
                     },
                     I32(val) => {
-                        int_map.insert(*hash,*val);
+                        int_map.insert(hash.0, *val);
                     },
                     U32(val) => {
-                        int_map.insert(*hash,*val as i32);
+                        int_map.insert(hash.0, *val as i32);
                     },
                     Float(val) => {
-                        float_map.insert(*hash, *val);
+                        float_map.insert(hash.0, *val);
                     },
                     Hash(val) => {
-                        let val = if let prc::hash40::Hash40(v) = val { v } else { unreachable!() };
-                        int64_map.insert(*hash, *val);
+                        int64_map.insert(hash.0, val.0);
                     },
+                    // nested params are only reachable by path, through the parsed tree
+                    Struct(_) | List(_) => {},
                     _ => {
-                        panic!("Invalid param kind: must be bool, int, int64, or float.");
+                        return Err(format!("Invalid param kind in \"{}\": must be bool, int, int64, float, struct or list.", path));
                     }
                 }
+                tree.push((prc::hash40::Hash40(hash.0), value.clone()));
             }
-        } else { unreachable!() }
-        int.insert(agent.clone(), Arc::new(int_map));
-        int64.insert(agent.clone(), Arc::new(int64_map));
-        float.insert(agent.clone(), Arc::new(float_map));
-        flag.insert(agent.clone(), Arc::new(flag_map));
+        }
+        AGENT_INT.write().insert(agent.clone(), Arc::new(int_map));
+        AGENT_INT64.write().insert(agent.clone(), Arc::new(int64_map));
+        AGENT_FLOAT.write().insert(agent.clone(), Arc::new(float_map));
+        AGENT_FLAG.write().insert(agent.clone(), Arc::new(flag_map));
+        AGENT_TREE.write().insert(agent.clone(), Arc::new(prc::ParamStruct(tree)));
+        Ok(())
     }
 
-    fn _get_int(&self, ty: ParamType, hash: u64) -> i32 {
+    fn _find_int(&mut self, ty: ParamType, hash: u64) -> Option<i32> {
+        self.check_generation();
         match ty {
-            ParamType::Common => {
-                if let Some(map) = self.common_int.as_ref() {
-                    *map.get(&hash).unwrap_or(&0)
-                } else {
-                    i32::default()
-                }
-            },
-            ParamType::Shared => {
-                if let Some(map) = self.shared_int.as_ref() {
-                    *map.get(&hash).unwrap_or(&0)
-                } else {
-                    i32::default()
-                }
-            },
-            ParamType::Agent => {
-                if let Some(map) = self.agent_int.as_ref() {
-                    *map.get(&hash).unwrap_or(&0)
-                } else {
-                    i32::default()
-                }
-            },
-            _ => { unreachable!() }
+            ParamType::Common => self.common_int.as_ref()?.get(&hash).copied(),
+            ParamType::Shared => self.shared_int.as_ref()?.get(&hash).copied(),
+            ParamType::Agent => self.agent_int.as_ref()?.get(&hash).copied(),
+            // resolution happens a level above, see `resolve`
+            ParamType::Resolve => None
         }
     }
 
-    fn _get_int64(&self, ty: ParamType, hash: u64) -> u64 {
+    fn _find_int64(&mut self, ty: ParamType, hash: u64) -> Option<u64> {
+        self.check_generation();
         match ty {
-            ParamType::Common => {
-                if let Some(map) = self.common_int64.as_ref() {
-                    *map.get(&hash).unwrap_or(&0)
-                } else {
-                    u64::default()
-                }
-            },
-            ParamType::Shared => {
-                if let Some(map) = self.shared_int64.as_ref() {
-                    *map.get(&hash).unwrap_or(&0)
-                } else {
-                    u64::default()
-                }
-            },
-            ParamType::Agent => {
-                if let Some(map) = self.agent_int64.as_ref() {
-                    *map.get(&hash).unwrap_or(&0)
-                } else {
-                    u64::default()
-                }
-            },
-            _ => { unreachable!() }
+            ParamType::Common => self.common_int64.as_ref()?.get(&hash).copied(),
+            ParamType::Shared => self.shared_int64.as_ref()?.get(&hash).copied(),
+            ParamType::Agent => self.agent_int64.as_ref()?.get(&hash).copied(),
+            ParamType::Resolve => None
         }
     }
 
-    fn _get_float(&self, ty: ParamType, hash: u64) -> f32 {
+    fn _find_float(&mut self, ty: ParamType, hash: u64) -> Option<f32> {
+        self.check_generation();
         match ty {
-            ParamType::Common => {
-                if let Some(map) = self.common_float.as_ref() {
-                    *map.get(&hash).unwrap_or(&0.0)
-                } else {
-                    f32::default()
-                }
-            },
-            ParamType::Shared => {
-                if let Some(map) = self.shared_float.as_ref() {
-                    *map.get(&hash).unwrap_or(&0.0)
-                } else {
-                    f32::default()
-                }
-            },
-            ParamType::Agent => {
-                if let Some(map) = self.agent_float.as_ref() {
-                    *map.get(&hash).unwrap_or(&0.0)
-                } else {
-                    f32::default()
-                }
-            },
-            _ => { unreachable!() }
+            ParamType::Common => self.common_float.as_ref()?.get(&hash).copied(),
+            ParamType::Shared => self.shared_float.as_ref()?.get(&hash).copied(),
+            ParamType::Agent => self.agent_float.as_ref()?.get(&hash).copied(),
+            ParamType::Resolve => None
         }
     }
 
-    fn _get_flag(&self, ty: ParamType, hash: u64) -> bool {
+    fn _find_flag(&mut self, ty: ParamType, hash: u64) -> Option<bool> {
+        self.check_generation();
         match ty {
-            ParamType::Common => {
-                if let Some(map) = self.common_flag.as_ref() {
-                    *map.get(&hash).unwrap_or(&false)
-                } else {
-                    false
-                }
-            },
+            ParamType::Common => self.common_flag.as_ref()?.get(&hash).copied(),
+            ParamType::Shared => self.shared_flag.as_ref()?.get(&hash).copied(),
+            ParamType::Agent => self.agent_flag.as_ref()?.get(&hash).copied(),
+            ParamType::Resolve => None
+        }
+    }
+
+    // The param at `path` in the tree for `ty`, see `lookup_path` for the syntax
+    fn _get_path(&mut self, ty: ParamType, path: &str) -> Option<&prc::ParamKind> {
+        self.check_generation();
+        match ty {
+            ParamType::Common => lookup_path(self.common_tree.as_ref()?, path),
             ParamType::Shared => {
-                if let Some(map) = self.shared_flag.as_ref() {
-                    *map.get(&hash).unwrap_or(&false)
-                } else {
-                    false
-                }
-            },
-            ParamType::Agent => {
-                if let Some(map) = self.agent_flag.as_ref() {
-                    *map.get(&hash).unwrap_or(&false)
-                } else {
-                    false
+                let prc::ParamStruct(params) = self.shared_tree.as_ref()?.as_ref();
+                match params.get(0) {
+                    Some((_, prc::ParamKind::List(prc::ParamList(fighters)))) => match fighters.get(self.agent_kind as usize) {
+                        Some(prc::ParamKind::Struct(fighter)) => lookup_path(fighter, path),
+                        _ => None
+                    },
+                    _ => None
                 }
             },
-            _ => { unreachable!() }
+            ParamType::Agent => lookup_path(self.agent_tree.as_ref()?, path),
+            ParamType::Resolve => None
+        }
+    }
+
+    // Looks the param up in the layer `ty` names, or for `ParamType::Resolve` in every layer from the most to the least
+    // specific one, falling back to `vanilla` when none of them have it. Params that no layer has are `Missing`
+    fn resolve<T, F>(ty: ParamType, mut find: F, vanilla: Option<&dyn Fn() -> Option<T>>) -> (T, ParamLayer)
+    where
+        T: Default,
+        F: FnMut(ParamType) -> Option<T>
+    {
+        let layers: &[(ParamType, ParamLayer)] = match ty {
+            ParamType::Common => &[(ParamType::Common, ParamLayer::Common)],
+            ParamType::Shared => &[(ParamType::Shared, ParamLayer::Shared)],
+            ParamType::Agent => &[(ParamType::Agent, ParamLayer::Agent)],
+            ParamType::Resolve => &RESOLVE_ORDER
+        };
+        for (ty, layer) in layers.iter() {
+            if let Some(value) = find(*ty) {
+                return (value, *layer);
+            }
+        }
+        match (ty, vanilla.and_then(|vanilla| vanilla())) {
+            (ParamType::Resolve, Some(value)) => (value, ParamLayer::Vanilla),
+            _ => (T::default(), ParamLayer::Missing)
+        }
+    }
+
+    // Like `WorkModule::get_param_*(boma, hash, 0)`, the fighter's own params are checked before the common ones. A key
+    // only falls through to the common table when the fighter's table doesn't have it, a value of 0 is still a value
+    fn vanilla_param<T, F: Fn(&prc::ParamKind) -> Option<T>>(fighter: Option<&HashMap<u64, prc::ParamKind>>, common: Option<&HashMap<u64, prc::ParamKind>>, hash: u64, convert: F) -> Option<T> {
+        match fighter.and_then(|x| x.get(&hash)) {
+            Some(kind) => convert(kind),
+            None => common.and_then(|x| x.get(&hash)).and_then(convert)
+        }
+    }
+
+    fn vanilla<T, F: Fn(&prc::ParamKind) -> Option<T>>(agent_kind: i32, hash: u64, convert: F) -> Option<T> {
+        let fighters = VANILLA_FIGHTER.read().clone();
+        let common = VANILLA_COMMON.read().clone();
+        let fighter = fighters.as_ref().and_then(|x| x.get(agent_kind as usize));
+        Self::vanilla_param(fighter, common.as_deref(), hash, convert)
+    }
+
+    fn int_by_hash(&mut self, ty: ParamType, hash: u64) -> (i32, ParamLayer) {
+        let agent_kind = self.agent_kind;
+        Self::resolve(ty, |ty| self._find_int(ty, hash), Some(&|| Self::vanilla(agent_kind, hash, kind_to_int)))
+    }
+
+    fn int64_by_hash(&mut self, ty: ParamType, hash: u64) -> (u64, ParamLayer) {
+        let agent_kind = self.agent_kind;
+        Self::resolve(ty, |ty| self._find_int64(ty, hash), Some(&|| Self::vanilla(agent_kind, hash, kind_to_int64)))
+    }
+
+    fn float_by_hash(&mut self, ty: ParamType, hash: u64) -> (f32, ParamLayer) {
+        let agent_kind = self.agent_kind;
+        Self::resolve(ty, |ty| self._find_float(ty, hash), Some(&|| Self::vanilla(agent_kind, hash, kind_to_float)))
+    }
+
+    fn flag_by_hash(&mut self, ty: ParamType, hash: u64) -> (bool, ParamLayer) {
+        let agent_kind = self.agent_kind;
+        // vanilla flags are mostly stored as ints
+        Self::resolve(ty, |ty| self._find_flag(ty, hash), Some(&|| Self::vanilla(agent_kind, hash, |kind| {
+            kind_to_flag(kind).or_else(|| kind_to_int(kind).map(|x| x != 0))
+        })))
+    }
+
+    // Plain names go through the flattened maps, anything with struct or list syntax through the tree.
+    // Vanilla params have no tree, so paths can't fall back to them
+    fn int_at(&mut self, ty: ParamType, string: &str) -> (i32, ParamLayer) {
+        if is_param_path(string) {
+            Self::resolve(ty, |ty| self._get_path(ty, string).and_then(kind_to_int), None)
+        } else {
+            self.int_by_hash(ty, smash::phx::Hash40::new(string).hash)
+        }
+    }
+
+    fn int64_at(&mut self, ty: ParamType, string: &str) -> (u64, ParamLayer) {
+        if is_param_path(string) {
+            Self::resolve(ty, |ty| self._get_path(ty, string).and_then(kind_to_int64), None)
+        } else {
+            self.int64_by_hash(ty, smash::phx::Hash40::new(string).hash)
         }
     }
 
-    pub(crate) fn handle_param_load(path: String, data: Vec<u8>) {
-        assert!(path.ends_with(".prc"), "ParamModule cannot handle non-param data types.");
+    fn float_at(&mut self, ty: ParamType, string: &str) -> (f32, ParamLayer) {
+        if is_param_path(string) {
+            Self::resolve(ty, |ty| self._get_path(ty, string).and_then(kind_to_float), None)
+        } else {
+            self.float_by_hash(ty, smash::phx::Hash40::new(string).hash)
+        }
+    }
+
+    fn flag_at(&mut self, ty: ParamType, string: &str) -> (bool, ParamLayer) {
+        if is_param_path(string) {
+            Self::resolve(ty, |ty| self._get_path(ty, string).and_then(kind_to_flag), None)
+        } else {
+            self.flag_by_hash(ty, smash::phx::Hash40::new(string).hash)
+        }
+    }
+
+    // The top level of a param struct keyed by hash, for lookups that have to tell a missing key apart from a 0
+    fn index_params(params: &prc::ParamStruct) -> HashMap<u64, prc::ParamKind> {
+        let prc::ParamStruct(params) = params;
+        params.iter().map(|(hash, value)| (hash.0, value.clone())).collect()
+    }
+
+    fn handle_vanilla_common(path: String, data: Result<Vec<u8>, crate::fs::LoadError>) -> Result<(), crate::fs::LoadError> {
+        let parsed = prc::read_stream(&mut Cursor::new(data?))
+            .map_err(|err| crate::fs::LoadError::HandlerRejected(format!("Could not parse the game's common.prc: {}", err)))?;
+        *VANILLA_COMMON.write() = Some(Arc::new(Self::index_params(&parsed)));
+        debugln!("[HDR::ParamModule] Loaded vanilla common params from \"{}\"", path);
+        Ok(())
+    }
+
+    fn handle_vanilla_fighter_param(path: String, data: Result<Vec<u8>, crate::fs::LoadError>) -> Result<(), crate::fs::LoadError> {
+        let parsed = prc::read_stream(&mut Cursor::new(data?))
+            .map_err(|err| crate::fs::LoadError::HandlerRejected(format!("Could not parse the game's fighter_param.prc: {}", err)))?;
+        // the same layout as HDR's fighter_param.prc, a single list with an entry for every fighter kind
+        let fighters = match parsed.0.get(0) {
+            Some((_, prc::ParamKind::List(prc::ParamList(list)))) if parsed.0.len() == 1 => list.iter()
+                .map(|x| match x {
+                    prc::ParamKind::Struct(params) => Ok(Self::index_params(params)),
+                    _ => Err(crate::fs::LoadError::HandlerRejected(String::from("Malformed vanilla fighter PRC.")))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(crate::fs::LoadError::HandlerRejected(String::from("Malformed vanilla fighter PRC.")))
+        };
+        *VANILLA_FIGHTER.write() = Some(Arc::new(fighters));
+        debugln!("[HDR::ParamModule] Loaded vanilla fighter params from \"{}\"", path);
+        Ok(())
+    }
+
+    // Queues the game's own param tables for `ParamType::Resolve`. Needs the `arc:/` mount
+    pub(crate) fn queue_vanilla_params() {
+        crate::fs::queue(vec![
+            crate::fs::LoadRequest::new(VANILLA_COMMON_PRC_PATH, Self::handle_vanilla_common),
+            crate::fs::LoadRequest::new(VANILLA_FIGHTER_PARAM_PRC_PATH, Self::handle_vanilla_fighter_param)
+        ]);
+    }
+
+    pub(crate) fn handle_param_load(path: String, data: Vec<u8>) -> Result<(), String> {
+        if !path.ends_with(".prc") {
+            return Err(String::from("ParamModule cannot handle non-param data types."));
+        }
         // probably a better way to handle this but I'm not interested at the moment
-        if path.starts_with("rom:/hdr/common/") {
+        // Files can come from any mount or layer, only the logical part of the path decides what they are
+        let logical = crate::fs::layer::logical_path(&path);
+        if logical.starts_with("hdr/common/") {
             if path.ends_with("common.prc") {
                 let mut buf = Cursor::new(data);
-                let parsed = prc::read_stream(&mut buf).expect("Could not parse HDR's common.prc");
-                Self::handle_common_prc(&parsed);
+                let parsed = prc::read_stream(&mut buf).map_err(|err| format!("Could not parse HDR's common.prc: {}", err))?;
+                Self::handle_common_prc(&parsed)?;
+                *COMMON_TREE.write() = Some(Arc::new(parsed));
             } else if path.ends_with("fighter_param.prc") {
                 let mut buf = Cursor::new(data);
-                let parsed = prc::read_stream(&mut buf).expect("Could not parse HDR's fighter_param.prc");
-                Self::handle_shared_prc(&parsed);
+                let parsed = prc::read_stream(&mut buf).map_err(|err| format!("Could not parse HDR's fighter_param.prc: {}", err))?;
+                Self::handle_shared_prc(&parsed)?;
+                *SHARED_FIGHTER_TREE.write() = Some(Arc::new(parsed));
             } else {
-                panic!("Common param file loaded that is not handled.");
+                return Err(String::from("Common param file loaded that is not handled."));
             }
         } else {
-            let tokens: Vec<String> = path.split('/').map(|x| String::from(x)).collect();
-            let agent = tokens.get(2).expect("Invalid unique fighter param path.");
+            let tokens: Vec<String> = logical.split('/').map(|x| String::from(x)).collect();
+            // Either hdr/<agent>/... or hdr/fighter/<agent>/...
+            let agent = match tokens.get(1) {
+                Some(dir) if dir == "fighter" => tokens.get(2),
+                agent => agent
+            };
+            let agent = agent.ok_or_else(|| String::from("Invalid unique fighter param path."))?;
             let mut buf = Cursor::new(data);
-            let parsed = prc::read_stream(&mut buf).expect("Could not parse fighter's param file.");
-            Self::handle_fighter_prc(agent, &parsed);
+            let parsed = prc::read_stream(&mut buf).map_err(|err| format!("Could not parse fighter's param file: {}", err))?;
+            let id = Self::handle_fighter_prc(agent, &path, parsed)?;
+            let agent = agent.clone();
+            let unload_path = path.clone();
+            crate::fs::on_unload(&path, move || Self::handle_param_unload(&agent, &unload_path, id));
         }
+        PARAM_GENERATION.fetch_add(1, Ordering::AcqRel);
         debugln!("loaded {}", path);
+        Ok(())
     }
 
-    pub(crate) fn handle_param_unload(info: &skyline::nro::NroInfo) {
-        let module = &String::from(info.name);
-        let mut int = AGENT_INT.write();
-        let mut int64 = AGENT_INT64.write();
-        let mut float = AGENT_FLOAT.write();
-        let mut flag = AGENT_FLAG.write();
-        int.remove(module);
-        int64.remove(module);
-        float.remove(module);
-        flag.remove(module);
+    // Removes one of the agent's param files, as long as it is still the version loaded by `id`
+    fn handle_param_unload(agent: &String, path: &str, id: u64) {
+        let mut files = AGENT_FILES.write();
+        let agent_files = match files.get_mut(agent) {
+            Some(agent_files) if agent_files.get(path).map_or(false, |x| x.0 == id) => agent_files,
+            _ => return
+        };
+        agent_files.remove(path);
+        if agent_files.is_empty() {
+            files.remove(agent);
+            AGENT_INT.write().remove(agent);
+            AGENT_INT64.write().remove(agent);
+            AGENT_FLOAT.write().remove(agent);
+            AGENT_FLAG.write().remove(agent);
+            AGENT_TREE.write().remove(agent);
+        } else if let Err(err) = Self::merge_fighter_prcs(agent, agent_files) {
+            // every remaining file was merged successfully when it was loaded, so this shouldn't happen
+            println!("[HDR::ParamModule] Failed to merge the remaining param files of {}: {}", agent, err);
+        }
+        drop(files);
+        PARAM_GENERATION.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Blocks until the param files this fighter relies on have been processed by the file manager, so that
+    // we don't race the loader during fighter init. Files which were never queued can't be waited on, and background
+    // files (hot reloads, entries the file map asks to load in the background) are left out since the budget can hold
+    // them back for longer than init may wait.
+    fn wait_for_params(agent_kind: i32) {
+        let mut handles = Vec::new();
+        for path in [COMMON_PRC_PATH, FIGHTER_PARAM_PRC_PATH, VANILLA_COMMON_PRC_PATH, VANILLA_FIGHTER_PARAM_PRC_PATH].iter() {
+            // the file map can name them either directly or as logical paths
+            handles.extend(crate::fs::handle(path));
+            handles.extend(crate::fs::handle(crate::fs::layer::logical_path(path)));
+        }
+        handles.extend(crate::fs::find_module_handles(|module| crate::utils::agent_to_agent_kind(module) == agent_kind));
+        handles.retain(|handle| handle.priority() != crate::fs::LoadPriority::Background);
+        if crate::fs::LoadHandle::wait_all(&handles, PARAM_LOAD_TIMEOUT).is_none() {
+            println!("[HDR::ParamModule] Timed out waiting for param files of fighter kind {}.", agent_kind);
+        }
     }
 
     pub fn new(category: i32, agent_kind: i32) -> Self {
         unsafe {
-            let mut ret = Self {
-                common_int: None,
-                common_int64: None,
-                common_float: None,
-                common_flag: None,
-                shared_int: None,
-                shared_int64: None,
-                shared_float: None,
-                shared_flag: None,
-                agent_int: None,
-                agent_int64: None,
-                agent_float: None,
-                agent_flag: None
-            };
-            if category == *smash::lib::lua_const::BATTLE_OBJECT_CATEGORY_FIGHTER && !cfg!(feature = "no_common_params") {
+            if category == *smash::lib::lua_const::BATTLE_OBJECT_CATEGORY_FIGHTER {
+                Self::wait_for_params(agent_kind);
+            }
+        }
+        let mut ret = Self {
+            common_int: None,
+            common_int64: None,
+            common_float: None,
+            common_flag: None,
+            shared_int: None,
+            shared_int64: None,
+            shared_float: None,
+            shared_flag: None,
+            agent_int: None,
+            agent_int64: None,
+            agent_float: None,
+            agent_flag: None,
+            common_tree: None,
+            shared_tree: None,
+            agent_tree: None,
+            category,
+            agent_kind,
+            generation: 0
+        };
+        ret.refresh();
+        ret
+    }
+
+    // Takes a new reference to the currently loaded param maps
+    fn refresh(&mut self) {
+        // Read the generation first, so that a reload which happens while we are copying gets picked up next lookup
+        self.generation = PARAM_GENERATION.load(Ordering::Acquire);
+        let agent_kind = self.agent_kind;
+        unsafe {
+            if self.category == *smash::lib::lua_const::BATTLE_OBJECT_CATEGORY_FIGHTER && !cfg!(feature = "no_common_params") {
                 let int = COMMON_INT.read();
                 let int64 = COMMON_INT64.read();
                 let float = COMMON_FLOAT.read();
                 let flag = COMMON_FLAG.read();
-                ret.common_int = Some(int.clone().expect("Common prc not loaded.").clone());
-                ret.common_int64 = Some(int64.clone().expect("Common prc not loaded.").clone());
-                ret.common_float = Some(float.clone().expect("Common prc not loaded.").clone());
-                ret.common_flag = Some(flag.clone().expect("Common prc not loaded.").clone());
+                if int.is_none() {
+                    println!("[HDR::ParamModule] Common prc not loaded, common params will read as default values.");
+                }
+                self.common_int = int.clone();
+                self.common_int64 = int64.clone();
+                self.common_float = float.clone();
+                self.common_flag = flag.clone();
+                self.common_tree = COMMON_TREE.read().clone();
     
                 let int = SHARED_FIGHTER_INT.read();
                 let int64 = SHARED_FIGHTER_INT64.read();
                 let float = SHARED_FIGHTER_FLOAT.read();
                 let flag = SHARED_FIGHTER_FLAG.read();
-                ret.shared_int = Some(int.clone().expect("Common prc not loaded.").get(agent_kind as usize).expect("Fighter missing from fighter params").clone());
-                ret.shared_int64 = Some(int64.clone().expect("Common prc not loaded.").get(agent_kind as usize).expect("Fighter missing from fighter params").clone());
-                ret.shared_float = Some(float.clone().expect("Common prc not loaded.").get(agent_kind as usize).expect("Fighter missing from fighter params").clone());
-                ret.shared_flag = Some(flag.clone().expect("Common prc not loaded.").get(agent_kind as usize).expect("Fighter missing from fighter params").clone());
-            }
-
-            let int = AGENT_INT.read();
-            let int64 = AGENT_INT64.read();
-            let float = AGENT_FLOAT.read();
-            let flag = AGENT_FLAG.read();
-            let mut _agent = None;
-            for (agent, params) in int.iter() {
-                if crate::utils::agent_to_agent_kind(agent) == agent_kind {
-                    _agent = Some(agent.clone());
-                    break;
+                self.shared_int = int.as_ref().and_then(|x| x.get(agent_kind as usize)).cloned();
+                self.shared_int64 = int64.as_ref().and_then(|x| x.get(agent_kind as usize)).cloned();
+                self.shared_float = float.as_ref().and_then(|x| x.get(agent_kind as usize)).cloned();
+                self.shared_flag = flag.as_ref().and_then(|x| x.get(agent_kind as usize)).cloned();
+                self.shared_tree = SHARED_FIGHTER_TREE.read().clone();
+                if self.shared_int.is_none() {
+                    println!("[HDR::ParamModule] Fighter kind {} missing from fighter params, shared params will read as default values.", agent_kind);
                 }
             }
-            if _agent.is_some() {
-                let agent = _agent.unwrap();
-                ret.agent_int = Some(int.get(&agent).expect("Invalid loaded PRC state.").clone());
-                ret.agent_int64 = Some(int64.get(&agent).expect("Invalid loaded PRC state.").clone());
-                ret.agent_float = Some(float.get(&agent).expect("Invalid loaded PRC state.").clone());
-                ret.agent_flag = Some(flag.get(&agent).expect("Invalid loaded PRC state.").clone());
+        }
+
+        let int = AGENT_INT.read();
+        let int64 = AGENT_INT64.read();
+        let float = AGENT_FLOAT.read();
+        let flag = AGENT_FLAG.read();
+        let mut _agent = None;
+        for (agent, params) in int.iter() {
+            if crate::utils::agent_to_agent_kind(agent) == agent_kind {
+                _agent = Some(agent.clone());
+                break;
             }
+        }
+        if _agent.is_some() {
+            let agent = _agent.unwrap();
+            self.agent_int = int.get(&agent).cloned();
+            self.agent_int64 = int64.get(&agent).cloned();
+            self.agent_float = float.get(&agent).cloned();
+            self.agent_flag = flag.get(&agent).cloned();
+            self.agent_tree = AGENT_TREE.read().get(&agent).cloned();
+        }
+    }
 
-            ret
+    // Called before every lookup so that live fighters see hot reloaded params
+    fn check_generation(&mut self) {
+        if self.generation != PARAM_GENERATION.load(Ordering::Acquire) {
+            self.refresh();
         }
     }
 
     #[cfg_attr(feature = "debug", export_name = "ParamModule__get_int")]
     pub fn get_int(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> i32 {
         unsafe {
-            get_param_module!(boma)._get_int(ty, smash::phx::Hash40::new(string).hash)
+            get_param_module!(boma).int_at(ty, string).0
         }
     }
 
     #[cfg_attr(feature = "debug", export_name = "ParamModule__get_int64")]
     pub fn get_int64(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> u64 {
         unsafe {
-            get_param_module!(boma)._get_int64(ty, smash::phx::Hash40::new(string).hash)
+            get_param_module!(boma).int64_at(ty, string).0
         }
     }
 
     #[cfg_attr(feature = "debug", export_name = "ParamModule__get_hash")]
     pub fn get_hash(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> smash::phx::Hash40 {
         unsafe {
-            smash::phx::Hash40::new_raw(get_param_module!(boma)._get_int64(ty, smash::phx::Hash40::new(string).hash))
+            smash::phx::Hash40::new_raw(get_param_module!(boma).int64_at(ty, string).0)
         }
     }
 
     #[cfg_attr(feature = "debug", export_name = "ParamModule__get_float")]
     pub fn get_float(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> f32 {
         unsafe {
-            get_param_module!(boma)._get_float(ty, smash::phx::Hash40::new(string).hash)
+            get_param_module!(boma).float_at(ty, string).0
         }
     }
 
@@ -496,8 +775,8 @@ impl ParamModule {
         unsafe {
             let module = get_param_module!(boma);
             smash::phx::Vector2f {
-                x: module._get_float(ty, smash::phx::Hash40::new(x).hash),
-                y: module._get_float(ty, smash::phx::Hash40::new(y).hash),
+                x: module.float_at(ty, x).0,
+                y: module.float_at(ty, y).0,
             }
         }
     }
@@ -507,9 +786,9 @@ impl ParamModule {
         unsafe {
             let module = get_param_module!(boma);
             smash::phx::Vector3f {
-                x: module._get_float(ty, smash::phx::Hash40::new(x).hash),
-                y: module._get_float(ty, smash::phx::Hash40::new(y).hash),
-                z: module._get_float(ty, smash::phx::Hash40::new(z).hash)
+                x: module.float_at(ty, x).0,
+                y: module.float_at(ty, y).0,
+                z: module.float_at(ty, z).0
             }
         }
     }
@@ -519,18 +798,344 @@ impl ParamModule {
         unsafe {
             let module = get_param_module!(boma);
             smash::phx::Vector4f {
-                x: module._get_float(ty, smash::phx::Hash40::new(x).hash),
-                y: module._get_float(ty, smash::phx::Hash40::new(y).hash),
-                z: module._get_float(ty, smash::phx::Hash40::new(z).hash),
-                w: module._get_float(ty, smash::phx::Hash40::new(w).hash)
+                x: module.float_at(ty, x).0,
+                y: module.float_at(ty, y).0,
+                z: module.float_at(ty, z).0,
+                w: module.float_at(ty, w).0
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_int_key")]
+    pub fn get_int_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, key: ParamKey) -> i32 {
+        unsafe {
+            get_param_module!(boma).int_by_hash(ty, key.0).0
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_int64_key")]
+    pub fn get_int64_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, key: ParamKey) -> u64 {
+        unsafe {
+            get_param_module!(boma).int64_by_hash(ty, key.0).0
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_hash_key")]
+    pub fn get_hash_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, key: ParamKey) -> smash::phx::Hash40 {
+        unsafe {
+            smash::phx::Hash40::new_raw(get_param_module!(boma).int64_by_hash(ty, key.0).0)
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_float_key")]
+    pub fn get_float_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, key: ParamKey) -> f32 {
+        unsafe {
+            get_param_module!(boma).float_by_hash(ty, key.0).0
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_vec2_key")]
+    pub fn get_vec2_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, x: ParamKey, y: ParamKey) -> smash::phx::Vector2f {
+        unsafe {
+            let module = get_param_module!(boma);
+            smash::phx::Vector2f {
+                x: module.float_by_hash(ty, x.0).0,
+                y: module.float_by_hash(ty, y.0).0,
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_vec3_key")]
+    pub fn get_vec3_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, x: ParamKey, y: ParamKey, z: ParamKey) -> smash::phx::Vector3f {
+        unsafe {
+            let module = get_param_module!(boma);
+            smash::phx::Vector3f {
+                x: module.float_by_hash(ty, x.0).0,
+                y: module.float_by_hash(ty, y.0).0,
+                z: module.float_by_hash(ty, z.0).0
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_vec4_key")]
+    pub fn get_vec4_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, x: ParamKey, y: ParamKey, z: ParamKey, w: ParamKey) -> smash::phx::Vector4f {
+        unsafe {
+            let module = get_param_module!(boma);
+            smash::phx::Vector4f {
+                x: module.float_by_hash(ty, x.0).0,
+                y: module.float_by_hash(ty, y.0).0,
+                z: module.float_by_hash(ty, z.0).0,
+                w: module.float_by_hash(ty, w.0).0
             }
         }
     }
 
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_flag_key")]
+    pub fn get_flag_key(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, key: ParamKey) -> bool {
+        unsafe {
+            get_param_module!(boma).flag_by_hash(ty, key.0).0
+        }
+    }
+
+    /// Like `get_int`, but also reports which layer the value came from
+    pub fn get_int_with_layer(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> (i32, ParamLayer) {
+        unsafe {
+            get_param_module!(boma).int_at(ty, string)
+        }
+    }
+
+    pub fn get_int64_with_layer(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> (u64, ParamLayer) {
+        unsafe {
+            get_param_module!(boma).int64_at(ty, string)
+        }
+    }
+
+    pub fn get_float_with_layer(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> (f32, ParamLayer) {
+        unsafe {
+            get_param_module!(boma).float_at(ty, string)
+        }
+    }
+
+    pub fn get_flag_with_layer(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> (bool, ParamLayer) {
+        unsafe {
+            get_param_module!(boma).flag_at(ty, string)
+        }
+    }
+
+    /// The amount of elements in the list at `path`, or 0 if there is no list there
+    #[cfg_attr(feature = "debug", export_name = "ParamModule__get_list_len")]
+    pub fn get_list_len(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, path: &str) -> usize {
+        unsafe {
+            let find = |module: &mut ParamModule, ty| match module._get_path(ty, path) {
+                Some(prc::ParamKind::List(prc::ParamList(list))) => Some(list.len()),
+                _ => None
+            };
+            get_param_module!(boma).resolve(ty, find, None).0
+        }
+    }
+
     #[cfg_attr(feature = "debug", export_name = "ParamModule__get_flag")]
     pub fn get_flag(boma: *mut smash::app::BattleObjectModuleAccessor, ty: ParamType, string: &str) -> bool {
         unsafe {
-            get_param_module!(boma)._get_flag(ty, smash::phx::Hash40::new(string).hash)
+            get_param_module!(boma).flag_at(ty, string).0
+        }
+    }
+}
+
+// Whether `string` has to be looked up through the tree, as opposed to being a top-level param name
+fn is_param_path(string: &str) -> bool {
+    string.contains('.') || string.contains('[')
+}
+
+fn struct_field<'a>(params: &'a prc::ParamStruct, name: &str) -> Option<&'a prc::ParamKind> {
+    let hash = crate::utils::hash40(name);
+    let prc::ParamStruct(fields) = params;
+    fields.iter().find(|(key, _)| key.0 == hash).map(|(_, value)| value)
+}
+
+// Looks up a nested param. Struct fields are separated by `.` and list elements are indexed with `[n]`,
+// for example "special_s.charge_frames[2]" or "hitboxes[0].damage"
+fn lookup_path<'a>(root: &'a prc::ParamStruct, path: &str) -> Option<&'a prc::ParamKind> {
+    let mut current: Option<&'a prc::ParamKind> = None;
+    for segment in path.split('.') {
+        let (name, mut indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        let parent = match current {
+            None => root,
+            Some(prc::ParamKind::Struct(params)) => params,
+            Some(_) => return None
+        };
+        let mut kind = struct_field(parent, name)?;
+        while !indices.is_empty() {
+            let end = indices.find(']')?;
+            let idx: usize = indices.get(1..end)?.parse().ok()?;
+            kind = match kind {
+                prc::ParamKind::List(prc::ParamList(list)) => list.get(idx)?,
+                _ => return None
+            };
+            indices = &indices[end + 1..];
         }
+        current = Some(kind);
     }
-}
\ No newline at end of file
+    current
+}
+
+fn kind_to_int(kind: &prc::ParamKind) -> Option<i32> {
+    use prc::ParamKind::*;
+    match kind {
+        I8(val) => Some(*val as i32),
+        U8(val) => Some(*val as i32),
+        I16(val) => Some(*val as i32),
+        U16(val) => Some(*val as i32),
+        I32(val) => Some(*val),
+        U32(val) => Some(*val as i32),
+        _ => None
+    }
+}
+
+fn kind_to_int64(kind: &prc::ParamKind) -> Option<u64> {
+    match kind {
+        prc::ParamKind::Hash(prc::hash40::Hash40(val)) => Some(*val),
+        _ => None
+    }
+}
+
+fn kind_to_float(kind: &prc::ParamKind) -> Option<f32> {
+    match kind {
+        prc::ParamKind::Float(val) => Some(*val),
+        _ => None
+    }
+}
+
+fn kind_to_flag(kind: &prc::ParamKind) -> Option<bool> {
+    match kind {
+        prc::ParamKind::Bool(val) => Some(*val),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hash40;
+    use prc::{hash40::Hash40, ParamKind, ParamList, ParamStruct};
+
+    fn param_struct(fields: Vec<(&str, ParamKind)>) -> ParamStruct {
+        ParamStruct(fields.into_iter().map(|(name, value)| (Hash40(hash40(name)), value)).collect())
+    }
+
+    fn tree() -> ParamStruct {
+        param_struct(vec![
+            ("plain", ParamKind::I32(7)),
+            ("special_s", ParamKind::Struct(param_struct(vec![
+                ("charge_frames", ParamKind::List(ParamList(vec![ParamKind::I32(1), ParamKind::I32(2), ParamKind::I32(3)])))
+            ]))),
+            ("hitboxes", ParamKind::List(ParamList(vec![
+                ParamKind::Struct(param_struct(vec![("damage", ParamKind::Float(3.5))]))
+            ])))
+        ])
+    }
+
+    #[test]
+    fn vanilla_params_fall_back_to_common() {
+        let mut fighter = HashMap::new();
+        fighter.insert(hash40("walk_speed_max"), ParamKind::Float(1.5));
+        fighter.insert(hash40("jump_count_max"), ParamKind::I32(0));
+        let mut common = HashMap::new();
+        common.insert(hash40("dash_speed"), ParamKind::Float(2.0));
+        common.insert(hash40("jump_count_max"), ParamKind::I32(2));
+        let find = |hash| ParamModule::vanilla_param(Some(&fighter), Some(&common), hash, kind_to_float);
+        assert_eq!(find(hash40("walk_speed_max")), Some(1.5));
+        assert_eq!(find(hash40("dash_speed")), Some(2.0));
+        assert_eq!(find(hash40("missing")), None);
+        // a fighter value of 0 is still the fighter's value
+        assert_eq!(ParamModule::vanilla_param(Some(&fighter), Some(&common), hash40("jump_count_max"), kind_to_int), Some(0));
+        assert_eq!(ParamModule::vanilla_param(None, None, hash40("dash_speed"), kind_to_float), None);
+    }
+
+    #[test]
+    fn resolves_through_every_layer() {
+        let shared = |ty| match ty {
+            ParamType::Shared => Some(2),
+            _ => None
+        };
+        let nowhere = |_| None::<i32>;
+        assert_eq!(ParamModule::resolve(ParamType::Resolve, shared, Some(&|| Some(3))), (2, ParamLayer::Shared));
+        assert_eq!(ParamModule::resolve(ParamType::Resolve, nowhere, Some(&|| Some(0))), (0, ParamLayer::Vanilla));
+        // no layer has the key, vanilla included
+        assert_eq!(ParamModule::resolve(ParamType::Resolve, nowhere, Some(&|| None)), (0, ParamLayer::Missing));
+        assert_eq!(ParamModule::resolve(ParamType::Resolve, nowhere, None), (0, ParamLayer::Missing));
+        // only `Resolve` falls back to vanilla
+        assert_eq!(ParamModule::resolve(ParamType::Agent, nowhere, Some(&|| Some(3))), (0, ParamLayer::Missing));
+        assert_eq!(ParamModule::resolve(ParamType::Shared, shared, None), (2, ParamLayer::Shared));
+    }
+
+    #[test]
+    fn looks_up_paths() {
+        let tree = tree();
+        assert_eq!(lookup_path(&tree, "plain").and_then(kind_to_int), Some(7));
+        assert_eq!(lookup_path(&tree, "special_s.charge_frames[2]").and_then(kind_to_int), Some(3));
+        assert_eq!(lookup_path(&tree, "hitboxes[0].damage").and_then(kind_to_float), Some(3.5));
+        assert!(lookup_path(&tree, "missing").is_none());
+        assert!(lookup_path(&tree, "plain.field").is_none());
+        assert!(lookup_path(&tree, "hitboxes[1]").is_none());
+        assert!(lookup_path(&tree, "hitboxes[x]").is_none());
+        assert!(lookup_path(&tree, "hitboxes[0").is_none());
+        assert!(lookup_path(&tree, "special_s.charge_frames[0][0]").is_none());
+        assert!(is_param_path("hitboxes[0].damage"));
+        assert!(!is_param_path("plain"));
+    }
+
+    #[test]
+    fn loads_agent_params_through_the_file_manager() {
+        let root = crate::fs::mount::tests::temp_dir("param-pipeline");
+        std::fs::create_dir_all(root.join("hdr/fighter/hdrtest_agent")).unwrap();
+        let mut data = Cursor::new(Vec::new());
+        prc::write_stream(&mut data, &tree()).unwrap();
+        std::fs::write(root.join("hdr/fighter/hdrtest_agent/param.prc"), data.into_inner()).unwrap();
+        crate::fs::register_mount("hdrtest-param:/", crate::fs::HostMount::read_only(&root));
+        crate::fs::register_handler("prc", ParamModule::handle_param_load, None);
+
+        let handles = crate::fs::queue(vec![
+            crate::fs::LoadRequest::new("hdrtest-param:/hdr/fighter/hdrtest_agent/param.prc", crate::fs::handle_load_file)
+                .with_owner("hdrtest_agent")
+        ]);
+        assert_eq!(handles[0].wait(), Ok(()));
+        assert_eq!(AGENT_INT.read()["hdrtest_agent"].get(&hash40("plain")), Some(&7));
+        let tree = AGENT_TREE.read()["hdrtest_agent"].clone();
+        assert_eq!(lookup_path(&tree, "special_s.charge_frames[2]").and_then(kind_to_int), Some(3));
+
+        // unloading the owning NRO releases the agent's params again
+        crate::fs::unload_module("hdrtest_agent");
+        assert!(!AGENT_INT.read().contains_key("hdrtest_agent"));
+        assert!(!AGENT_TREE.read().contains_key("hdrtest_agent"));
+        crate::fs::unregister_mount("hdrtest-param:/");
+    }
+
+    fn prc_bytes(tree: &ParamStruct) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        prc::write_stream(&mut data, tree).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn merges_every_param_file_of_an_agent() {
+        let a = "hdrtest:/hdr/fighter/hdrtest_merge/a.prc";
+        let b = "hdrtest:/hdr/fighter/hdrtest_merge/b.prc";
+        let agent = String::from("hdrtest_merge");
+        ParamModule::handle_param_load(String::from(a), prc_bytes(&param_struct(vec![
+            ("shared", ParamKind::I32(1)),
+            ("only_a", ParamKind::I32(2))
+        ]))).unwrap();
+        ParamModule::handle_param_load(String::from(b), prc_bytes(&param_struct(vec![
+            ("shared", ParamKind::I32(3)),
+            ("only_b", ParamKind::Float(4.0))
+        ]))).unwrap();
+        let int = AGENT_INT.read()[&agent].clone();
+        assert_eq!(int.get(&hash40("only_a")), Some(&2));
+        // files are merged in path order, so a.prc wins
+        assert_eq!(int.get(&hash40("shared")), Some(&1));
+        assert_eq!(AGENT_FLOAT.read()[&agent].get(&hash40("only_b")), Some(&4.0));
+        assert_eq!(lookup_path(&AGENT_TREE.read()[&agent], "only_b").and_then(kind_to_float), Some(4.0));
+
+        // unloading one of the files keeps the params of the other
+        crate::fs::unload_file(a, None);
+        let int = AGENT_INT.read()[&agent].clone();
+        assert_eq!(int.get(&hash40("shared")), Some(&3));
+        assert_eq!(int.get(&hash40("only_a")), None);
+        crate::fs::unload_file(b, None);
+        assert!(!AGENT_INT.read().contains_key(&agent));
+        assert!(!AGENT_FILES.read().contains_key(&agent));
+    }
+
+    #[test]
+    fn ignores_unloads_of_replaced_files() {
+        let path = "hdrtest:/hdr/fighter/hdrtest_reload/param.prc";
+        let agent = String::from("hdrtest_reload");
+        let first = ParamModule::handle_fighter_prc(&agent, path, param_struct(vec![("value", ParamKind::I32(1))])).unwrap();
+        let second = ParamModule::handle_fighter_prc(&agent, path, param_struct(vec![("value", ParamKind::I32(2))])).unwrap();
+        ParamModule::handle_param_unload(&agent, path, first);
+        assert_eq!(AGENT_INT.read()[&agent].get(&hash40("value")), Some(&2));
+        ParamModule::handle_param_unload(&agent, path, second);
+        assert!(!AGENT_INT.read().contains_key(&agent));
+    }
+}
@@ -138,4 +138,41 @@ pub fn agent_to_agent_kind<S: Into<String>>(agent: S) -> i32 {
             {}
     }
     kind
-}
\ No newline at end of file
+}
+
+// crc32 (IEEE) without a lookup table so that it can be evaluated at compile time. Only meant for short strings such
+// as param names, file contents are hashed with crc32fast
+pub const fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        crc ^= bytes[idx] as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        idx += 1;
+    }
+    !crc
+}
+
+// Same hashing as smash::phx::Hash40::new, but usable off-console and in const contexts
+pub const fn hash40(string: &str) -> u64 {
+    ((string.len() as u64) << 32) | (crc32(string.as_bytes()) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_known_values() {
+        // the standard crc32 (IEEE) check value
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+        // the length goes in the top byte, as in Hash40::new
+        assert_eq!(hash40("walk_speed_max"), 0x0e_e2ec2860);
+        assert_eq!(hash40("fighter_param_table"), 0x13_1f0d7ade);
+    }
+}